@@ -0,0 +1,151 @@
+#![no_std]
+
+// Indexes into the COUNTERS per-cpu array. Each slot holds a monotonically increasing number of events seen by the ebpf program.
+/// Number of IPv4 packets carrying options (i.e. IHL greater than 5).
+pub const COUNTER_IPV4_OPTIONS: u32 = 0;
+/// Number of IPv6 packets dropped from accounting because their extension header chain exceeded the configured depth.
+pub const COUNTER_IPV6_EXT_TOO_DEEP: u32 = 1;
+/// Number of IPv6 packets dropped from accounting because their extension header chain was truncated.
+pub const COUNTER_IPV6_EXT_TRUNCATED: u32 = 2;
+/// Number of non-first IPv4 fragments seen. They carry no transport header and are not attributed to a port.
+pub const COUNTER_IPV4_FRAGMENTS: u32 = 3;
+/// Number of non-first IPv6 fragments seen. They carry no transport header and are not attributed to a port.
+pub const COUNTER_IPV6_FRAGMENTS: u32 = 4;
+/// Number of flows that could not be recorded because the IP_V4 map was full (both generations).
+pub const COUNTER_INSERT_FAILURES_IP_V4: u32 = 5;
+/// Number of flows that could not be recorded because the IP_V6 map was full (both generations).
+pub const COUNTER_INSERT_FAILURES_IP_V6: u32 = 6;
+/// Number of flows that could not be recorded because the ICMP_V4 map was full (both generations).
+pub const COUNTER_INSERT_FAILURES_ICMP_V4: u32 = 7;
+/// Number of flows that could not be recorded because the ICMP_V6 map was full (both generations).
+pub const COUNTER_INSERT_FAILURES_ICMP_V6: u32 = 8;
+/// Number of (network, protocol, port) sketches that could not be recorded because the HLL map was full (both generations).
+pub const COUNTER_INSERT_FAILURES_HLL: u32 = 9;
+/// Number of slots in the COUNTERS array.
+pub const COUNTERS_LEN: u32 = 10;
+
+/// Upper bound on the number of IPv6 extension headers the ebpf program walks through.
+/// The actual limit is set from user-space and can not exceed this value.
+pub const IPV6_EXT_HEADERS_MAX: u8 = 8;
+
+// Kinds of tunnels the ebpf program can decapsulate (see FlowMeta::tunnel).
+pub const TUNNEL_NONE: u8 = 0;
+pub const TUNNEL_GRE: u8 = 1;
+pub const TUNNEL_IPIP: u8 = 2;
+pub const TUNNEL_6IN4: u8 = 3;
+pub const TUNNEL_VXLAN: u8 = 4;
+pub const TUNNEL_GENEVE: u8 = 5;
+
+/// The part of a flow key shared between IPv4 and IPv6: what the source has reached out to and how the packet got here.
+/// All fields are in host's endianness.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct FlowMeta {
+    /// VXLAN/GENEVE VNI or GRE key of the tunnel the packet was carried in, 0 if there was none.
+    pub vni: u32,
+    /// Index of the interface the packet was received on.
+    pub ifindex: u32,
+    /// Destination port, or the ICMP type and code packed by icmp_port() in the ICMP maps.
+    pub port: u16,
+    /// VLAN ID of the innermost 802.1Q/802.1ad tag, 0 if the frame was untagged.
+    pub vlan: u16,
+    pub proto: u8,
+    /// One of the TUNNEL_* constants.
+    pub tunnel: u8,
+    // Explicit padding so that no uninitialized bytes end up in the map key.
+    pub _pad: [u8; 2],
+}
+impl FlowMeta {
+    pub fn new(ifindex: u32, proto: u8, port: u16, vlan: u16, tunnel: u8, vni: u32) -> Self {
+        Self {
+            vni,
+            ifindex,
+            port,
+            vlan,
+            proto,
+            tunnel,
+            _pad: [0; 2],
+        }
+    }
+}
+
+/// Packs an ICMP/ICMPv6 type and code into FlowMeta's port field, as the ICMP maps are keyed by them instead of a port.
+pub fn icmp_port(icmp_type: u8, code: u8) -> u16 {
+    (icmp_type as u16) << 8 | code as u16
+}
+
+/// Key of the IP_V4 and ICMP_V4 maps: a source address along with the L4 protocol and destination port it has reached out to.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct FlowV4 {
+    pub addr: [u8; 4],
+    pub meta: FlowMeta,
+}
+
+/// Key of the IP_V6 and ICMP_V6 maps: a source address along with the L4 protocol and destination port it has reached out to.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct FlowV6 {
+    pub addr: [u16; 8],
+    pub meta: FlowMeta,
+}
+
+/// Value of the IP_V4, IP_V6, ICMP_V4 and ICMP_V6 per-cpu maps: how much traffic a flow has sent since it was last read.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FlowStats {
+    pub packets: u64,
+    /// Sum of the frame lengths, L2 header included.
+    pub bytes: u64,
+    /// bpf_ktime_get_ns() of the first and last packet, i.e. nanoseconds since boot (CLOCK_MONOTONIC).
+    pub first_seen: u64,
+    pub last_seen: u64,
+}
+
+/// Number of bits of a source's hash used to pick a HyperLogLog register.
+pub const HLL_PRECISION: u32 = 10;
+/// Number of registers of a HyperLogLog sketch. The standard error of its estimates is 1.04 / sqrt(HLL_REGISTERS), about 3.25%.
+pub const HLL_REGISTERS: usize = 1 << HLL_PRECISION;
+
+/// Registers of a HyperLogLog sketch, each holding the highest rank seen for the sources that map to it.
+pub type HllRegisters = [u8; HLL_REGISTERS];
+
+/// Key of the HLL maps: which sketch a source is added to. All fields are in host's endianness.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct HllKey {
+    /// Index of the interface the sources were seen on.
+    pub ifindex: u32,
+    pub port: u16,
+    pub proto: u8,
+    /// 4 for IPv4 and 6 for IPv6.
+    pub network: u8,
+}
+
+/// Mixes the bits of `x` so that the result is uniformly distributed (finalizer of MurmurHash3).
+pub fn hash64(mut x: u64) -> u64 {
+    x ^= x >> 33;
+    x = x.wrapping_mul(0xff51afd7ed558ccd);
+    x ^= x >> 33;
+    x = x.wrapping_mul(0xc4ceb9fe1a85ec53);
+    x ^= x >> 33;
+    x
+}
+
+/// Returns the register a source with the given `hash` maps to along with the rank to store in it,
+/// i.e. the position of the leftmost 1-bit in the remaining bits of the hash.
+pub fn hll_position(hash: u64) -> (usize, u8) {
+    let index = (hash >> (64 - HLL_PRECISION)) as usize;
+    // The trailing 1 caps the rank at 64 - HLL_PRECISION + 1
+    let rest = (hash << HLL_PRECISION) | (1 << (HLL_PRECISION - 1));
+    (index, rest.leading_zeros() as u8 + 1)
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for FlowV4 {}
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for FlowV6 {}
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for FlowStats {}
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for HllKey {}
@@ -1,11 +1,20 @@
 use aya_bpf::{
+    helpers::bpf_ktime_get_ns,
     macros::map,
-    maps::{Array, HashMap},
+    maps::{Array, HashMap, PerCpuArray, PerCpuHashMap},
+};
+use xdp_ip_counter_common::{
+    hash64, hll_position, FlowMeta, FlowStats, FlowV4, FlowV6, HllKey, HllRegisters, COUNTERS_LEN,
+    COUNTER_INSERT_FAILURES_HLL, COUNTER_INSERT_FAILURES_ICMP_V4, COUNTER_INSERT_FAILURES_ICMP_V6,
+    COUNTER_INSERT_FAILURES_IP_V4, COUNTER_INSERT_FAILURES_IP_V6, HLL_REGISTERS,
 };
-use network_types::ip::IpProto;
 
 pub const PORTS_MAP_SIZE: u32 = 100;
+// Default capacity of the flow maps. User-space may change it, along with their type (hash or LRU hash), at load time.
 pub const PACKETS_MAP_SIZE: u32 = 10240;
+// Default capacity of the HLL maps, changed by user-space at load time as well.
+pub const HLL_MAP_SIZE: u32 = 1024;
+pub const L3_IFACES_MAP_SIZE: u32 = 1024;
 
 #[map(name = "USE_CUSTOM_PORTS")]
 pub static mut USE_CUSTOM_PORTS: Array<u8> = Array::with_max_entries(1, 0);
@@ -14,134 +23,326 @@ pub static mut USE_CUSTOM_PORTS: Array<u8> = Array::with_max_entries(1, 0);
 pub static mut CUSTOM_PORTS: HashMap<u16, u8> =
     HashMap::<u16, u8>::with_max_entries(PORTS_MAP_SIZE, 0);
 
-#[map(name = "TCP_IP_V4")]
-pub static mut TCP_IP_V4: HashMap<[u8; 4], u16> =
-    HashMap::<[u8; 4], u16>::with_max_entries(PACKETS_MAP_SIZE, 0);
+// Interfaces the TC classifiers are attached to whose packets have no Ethernet header (tun, WireGuard, IP tunnels...),
+// keyed by index. Filled in from user-space as they are attached to.
+#[map(name = "L3_IFACES")]
+pub static mut L3_IFACES: HashMap<u32, u8> =
+    HashMap::<u32, u8>::with_max_entries(L3_IFACES_MAP_SIZE, 0);
 
-#[map(name = "UDP_IP_V4")]
-pub static mut UDP_IP_V4: HashMap<[u8; 4], u16> =
-    HashMap::<[u8; 4], u16>::with_max_entries(PACKETS_MAP_SIZE, 0);
+#[map(name = "DECAP")]
+pub static mut DECAP: Array<u8> = Array::with_max_entries(1, 0);
 
-#[map(name = "TCP_IP_V6")]
-pub static mut TCP_IP_V6: HashMap<[u16; 8], u16> =
-    HashMap::<[u16; 8], u16>::with_max_entries(PACKETS_MAP_SIZE, 0);
+#[map(name = "IPV6_EXT_MAX_DEPTH")]
+pub static mut IPV6_EXT_MAX_DEPTH: Array<u8> = Array::with_max_entries(1, 0);
 
-#[map(name = "UDP_IP_V6")]
-pub static mut UDP_IP_V6: HashMap<[u16; 8], u16> =
-    HashMap::<[u16; 8], u16>::with_max_entries(PACKETS_MAP_SIZE, 0);
+// Which of the two generations of flow maps (e.g. IP_V4_0 or IP_V4_1) packets are recorded into.
+// User-space flips it on each sample and drains the generation that is no longer written to.
+#[map(name = "GENERATION")]
+pub static mut GENERATION: Array<u32> = Array::with_max_entries(1, 0);
 
-pub fn add_v4<'a>(ip_proto: IpProto, ip: &[u8; 4], port: &u16) -> Result<(), &'a str> {
-    // Converting IP and Port from Network's endianness to host's endianness
-    let ip = ipv4_from_be(ip);
-    let port = u16::from_be(*port);
+#[map(name = "HLL_MODE")]
+pub static mut HLL_MODE: Array<u8> = Array::with_max_entries(1, 0);
 
-    match unsafe { USE_CUSTOM_PORTS.get(0) } {
-        Some(use_custom_ports) => {
-            if *use_custom_ports == 1 {
-                if unsafe { CUSTOM_PORTS.get(&port).is_some() } {
-                    match ip_proto {
-                        IpProto::Tcp => {
-                            if unsafe { TCP_IP_V4.get(&ip).is_none() } {
-                                match unsafe { TCP_IP_V4.insert(&ip, &port, 0) } {
-                                    Ok(_) => {}
-                                    Err(_) => return Err("failed to insert into TCP_IP_V4"),
-                                }
-                            }
-                        }
-                        IpProto::Udp => {
-                            if unsafe { UDP_IP_V4.get(&ip).is_none() } {
-                                match unsafe { UDP_IP_V4.insert(&ip, &port, 0) } {
-                                    Ok(_) => {}
-                                    Err(_) => return Err("failed to insert into UDP_IP_V4"),
-                                }
-                            }
-                        }
-                        _ => {}
-                    }
-                }
-            } else {
-                match ip_proto {
-                    IpProto::Tcp => {
-                        if unsafe { TCP_IP_V4.get(&ip).is_none() } {
-                            match unsafe { TCP_IP_V4.insert(&ip, &port, 0) } {
-                                Ok(_) => {}
-                                Err(_) => return Err("failed to insert into TCP_IP_V4"),
-                            }
-                        }
-                    }
-                    IpProto::Udp => {
-                        if unsafe { UDP_IP_V4.get(&ip).is_none() } {
-                            match unsafe { UDP_IP_V4.insert(&ip, &port, 0) } {
-                                Ok(_) => {}
-                                Err(_) => return Err("failed to insert into UDP_IP_V4"),
-                            }
-                        }
-                    }
-                    _ => {}
-                }
+#[map(name = "COUNTERS")]
+pub static mut COUNTERS: PerCpuArray<u64> = PerCpuArray::with_max_entries(COUNTERS_LEN, 0);
+
+#[map(name = "IP_V4_0")]
+pub static mut IP_V4_0: PerCpuHashMap<FlowV4, FlowStats> =
+    PerCpuHashMap::<FlowV4, FlowStats>::with_max_entries(PACKETS_MAP_SIZE, 0);
+
+#[map(name = "IP_V4_1")]
+pub static mut IP_V4_1: PerCpuHashMap<FlowV4, FlowStats> =
+    PerCpuHashMap::<FlowV4, FlowStats>::with_max_entries(PACKETS_MAP_SIZE, 0);
+
+#[map(name = "IP_V6_0")]
+pub static mut IP_V6_0: PerCpuHashMap<FlowV6, FlowStats> =
+    PerCpuHashMap::<FlowV6, FlowStats>::with_max_entries(PACKETS_MAP_SIZE, 0);
+
+#[map(name = "IP_V6_1")]
+pub static mut IP_V6_1: PerCpuHashMap<FlowV6, FlowStats> =
+    PerCpuHashMap::<FlowV6, FlowStats>::with_max_entries(PACKETS_MAP_SIZE, 0);
+
+#[map(name = "ICMP_V4_0")]
+pub static mut ICMP_V4_0: PerCpuHashMap<FlowV4, FlowStats> =
+    PerCpuHashMap::<FlowV4, FlowStats>::with_max_entries(PACKETS_MAP_SIZE, 0);
+
+#[map(name = "ICMP_V4_1")]
+pub static mut ICMP_V4_1: PerCpuHashMap<FlowV4, FlowStats> =
+    PerCpuHashMap::<FlowV4, FlowStats>::with_max_entries(PACKETS_MAP_SIZE, 0);
+
+#[map(name = "ICMP_V6_0")]
+pub static mut ICMP_V6_0: PerCpuHashMap<FlowV6, FlowStats> =
+    PerCpuHashMap::<FlowV6, FlowStats>::with_max_entries(PACKETS_MAP_SIZE, 0);
+
+#[map(name = "ICMP_V6_1")]
+pub static mut ICMP_V6_1: PerCpuHashMap<FlowV6, FlowStats> =
+    PerCpuHashMap::<FlowV6, FlowStats>::with_max_entries(PACKETS_MAP_SIZE, 0);
+
+// HyperLogLog sketches of the sources per (network, protocol, port), used instead of IP_V4 and IP_V6 in HLL mode.
+// Generations work the same way as for the flow maps.
+#[map(name = "HLL_0")]
+pub static mut HLL_0: PerCpuHashMap<HllKey, HllRegisters> =
+    PerCpuHashMap::<HllKey, HllRegisters>::with_max_entries(HLL_MAP_SIZE, 0);
+
+#[map(name = "HLL_1")]
+pub static mut HLL_1: PerCpuHashMap<HllKey, HllRegisters> =
+    PerCpuHashMap::<HllKey, HllRegisters>::with_max_entries(HLL_MAP_SIZE, 0);
+
+// Never written to. New sketches are inserted from it as they are too large to be built on the stack.
+#[map(name = "HLL_EMPTY")]
+pub static mut HLL_EMPTY: Array<HllRegisters> = Array::with_max_entries(1, 0);
+
+/// Records that `ip` (in network's endianness) has sent a `bytes` long packet to what `meta` describes.
+pub fn add_v4<'a>(ip: &[u8; 4], meta: FlowMeta, bytes: u64) -> Result<(), &'a str> {
+    if !is_port_tracked(meta.port)? {
+        return Ok(());
+    }
+
+    // Converting IP from Network's endianness to host's endianness
+    let flow = FlowV4 {
+        addr: ipv4_from_be(ip),
+        meta,
+    };
+
+    account(
+        match generation()? {
+            0 => unsafe { &IP_V4_0 },
+            _ => unsafe { &IP_V4_1 },
+        },
+        &flow,
+        bytes,
+        COUNTER_INSERT_FAILURES_IP_V4,
+        "failed to insert into IP_V4",
+    )
+}
+
+/// Records that `ip` (in network's endianness) has sent a `bytes` long packet to what `meta` describes.
+pub fn add_v6<'a>(ip: &[u16; 8], meta: FlowMeta, bytes: u64) -> Result<(), &'a str> {
+    if !is_port_tracked(meta.port)? {
+        return Ok(());
+    }
+
+    // Converting IP from Network's endianness to host's endianness
+    let flow = FlowV6 {
+        addr: ipv6_from_be(ip),
+        meta,
+    };
+
+    account(
+        match generation()? {
+            0 => unsafe { &IP_V6_0 },
+            _ => unsafe { &IP_V6_1 },
+        },
+        &flow,
+        bytes,
+        COUNTER_INSERT_FAILURES_IP_V6,
+        "failed to insert into IP_V6",
+    )
+}
+
+/// Records that `ip` (in network's endianness) has sent a `bytes` long packet of the ICMP type and code `meta` describes.
+/// ICMP has no ports, so the --ports filter does not apply.
+pub fn add_icmp_v4<'a>(ip: &[u8; 4], meta: FlowMeta, bytes: u64) -> Result<(), &'a str> {
+    let flow = FlowV4 {
+        addr: ipv4_from_be(ip),
+        meta,
+    };
+
+    account(
+        match generation()? {
+            0 => unsafe { &ICMP_V4_0 },
+            _ => unsafe { &ICMP_V4_1 },
+        },
+        &flow,
+        bytes,
+        COUNTER_INSERT_FAILURES_ICMP_V4,
+        "failed to insert into ICMP_V4",
+    )
+}
+
+/// Records that `ip` (in network's endianness) has sent a `bytes` long packet of the ICMPv6 type and code `meta` describes.
+/// ICMPv6 has no ports, so the --ports filter does not apply.
+pub fn add_icmp_v6<'a>(ip: &[u16; 8], meta: FlowMeta, bytes: u64) -> Result<(), &'a str> {
+    let flow = FlowV6 {
+        addr: ipv6_from_be(ip),
+        meta,
+    };
+
+    account(
+        match generation()? {
+            0 => unsafe { &ICMP_V6_0 },
+            _ => unsafe { &ICMP_V6_1 },
+        },
+        &flow,
+        bytes,
+        COUNTER_INSERT_FAILURES_ICMP_V6,
+        "failed to insert into ICMP_V6",
+    )
+}
+
+/// Adds `ip` (in network's endianness) to the HyperLogLog sketch of the protocol and port `meta` describes.
+pub fn add_hll_v4<'a>(ip: &[u8; 4], meta: FlowMeta) -> Result<(), &'a str> {
+    if !is_port_tracked(meta.port)? {
+        return Ok(());
+    }
+
+    let hash = hash64(u32::from_ne_bytes(*ip) as u64);
+    add_hll(4, hash, meta)
+}
+
+/// Adds `ip` (in network's endianness) to the HyperLogLog sketch of the protocol and port `meta` describes.
+pub fn add_hll_v6<'a>(ip: &[u16; 8], meta: FlowMeta) -> Result<(), &'a str> {
+    if !is_port_tracked(meta.port)? {
+        return Ok(());
+    }
+
+    let mut hash = 0;
+    for word in ip.iter() {
+        hash = hash64(hash ^ *word as u64);
+    }
+    add_hll(6, hash, meta)
+}
+
+fn add_hll<'a>(network: u8, hash: u64, meta: FlowMeta) -> Result<(), &'a str> {
+    let key = HllKey {
+        ifindex: meta.ifindex,
+        port: meta.port,
+        proto: meta.proto,
+        network,
+    };
+    let map = match generation()? {
+        0 => unsafe { &HLL_0 },
+        _ => unsafe { &HLL_1 },
+    };
+
+    let registers = match map.get_ptr_mut(&key) {
+        Some(registers) => registers,
+        None => {
+            let empty = match unsafe { HLL_EMPTY.get(0) } {
+                Some(empty) => empty,
+                None => return Err("failed to get element 0 from HLL_EMPTY array"),
+            };
+            if map.insert(&key, empty, 0).is_err() {
+                increment_counter(COUNTER_INSERT_FAILURES_HLL);
+                return Err("failed to insert into HLL");
+            }
+            match map.get_ptr_mut(&key) {
+                Some(registers) => registers,
+                None => return Err("failed to get inserted sketch from HLL"),
             }
         }
-        None => return Err("failed to get element 0 from USE_CUSTOM_PORTS array"),
+    };
+
+    // Sketches are per-cpu, they get merged in user-space
+    let (index, rank) = hll_position(hash);
+    let register = unsafe { &mut (*registers)[index & (HLL_REGISTERS - 1)] };
+    if rank > *register {
+        *register = rank;
     }
 
     Ok(())
 }
 
-pub fn add_v6<'a>(ip_proto: IpProto, ip: &[u16; 8], port: &u16) -> Result<(), &'a str> {
-    // Converting IP and Port from Network's endianness to host's endianness
-    let ip = ipv6_from_be(ip);
-    let port = u16::from_be(*port);
+/// Adds a packet of `bytes` to the FlowStats of `flow` on the current CPU, creating them if `flow` is new.
+/// As each CPU has its own copy of the stats, no atomic operations are needed.
+/// Failing to insert `flow` (i.e. the map is full) is counted in the COUNTERS slot at `failures`.
+fn account<'a, K>(
+    map: &PerCpuHashMap<K, FlowStats>,
+    flow: &K,
+    bytes: u64,
+    failures: u32,
+    err: &'a str,
+) -> Result<(), &'a str> {
+    let now = unsafe { bpf_ktime_get_ns() };
+    match map.get_ptr_mut(flow) {
+        Some(stats) => {
+            unsafe {
+                // The entry may have been created by another CPU, leaving this CPU's copy zeroed
+                if (*stats).packets == 0 {
+                    (*stats).first_seen = now;
+                }
+                (*stats).packets += 1;
+                (*stats).bytes += bytes;
+                (*stats).last_seen = now;
+            }
+            Ok(())
+        }
+        None => match map.insert(
+            flow,
+            &FlowStats {
+                packets: 1,
+                bytes,
+                first_seen: now,
+                last_seen: now,
+            },
+            0,
+        ) {
+            Ok(_) => Ok(()),
+            Err(_) => {
+                increment_counter(failures);
+                Err(err)
+            }
+        },
+    }
+}
+
+/// Increments the COUNTERS slot at `index` (see xdp_ip_counter_common for available indexes).
+pub fn increment_counter(index: u32) {
+    if let Some(counter) = unsafe { COUNTERS.get_ptr_mut(index) } {
+        unsafe { *counter += 1 };
+    }
+}
 
+/// Returns whether tunnels should be decapsulated to count inner flows, as set from user-space via the --decap flag.
+pub fn decap_enabled<'a>() -> Result<bool, &'a str> {
+    match unsafe { DECAP.get(0) } {
+        Some(decap) => Ok(*decap == 1),
+        None => Err("failed to get element 0 from DECAP array"),
+    }
+}
+
+/// Returns whether packets of interface `ifindex` start with their IP header rather than an Ethernet header.
+pub fn is_l3_iface(ifindex: u32) -> bool {
+    unsafe { L3_IFACES.get(&ifindex).is_some() }
+}
+
+/// Returns whether sources should be added to HyperLogLog sketches instead of IP_V4 and IP_V6, as set from user-space via the --hll flag.
+pub fn hll_enabled<'a>() -> Result<bool, &'a str> {
+    match unsafe { HLL_MODE.get(0) } {
+        Some(hll) => Ok(*hll == 1),
+        None => Err("failed to get element 0 from HLL_MODE array"),
+    }
+}
+
+/// Returns the maximum number of IPv6 extension headers to walk through, as set from user-space.
+pub fn ipv6_ext_max_depth<'a>() -> Result<u8, &'a str> {
+    match unsafe { IPV6_EXT_MAX_DEPTH.get(0) } {
+        Some(depth) => Ok(*depth),
+        None => Err("failed to get element 0 from IPV6_EXT_MAX_DEPTH array"),
+    }
+}
+
+/// Returns the generation of flow maps packets should be recorded into, as set from user-space.
+fn generation<'a>() -> Result<u32, &'a str> {
+    match unsafe { GENERATION.get(0) } {
+        Some(generation) => Ok(*generation),
+        None => Err("failed to get element 0 from GENERATION array"),
+    }
+}
+
+/// Returns whether packets destined to `port` (in host's endianness) should be recorded,
+/// based on the ports passed in from user-space via the --ports flag.
+fn is_port_tracked<'a>(port: u16) -> Result<bool, &'a str> {
     match unsafe { USE_CUSTOM_PORTS.get(0) } {
         Some(use_custom_ports) => {
             if *use_custom_ports == 1 {
-                if unsafe { CUSTOM_PORTS.get(&port).is_some() } {
-                    match ip_proto {
-                        IpProto::Tcp => {
-                            if unsafe { TCP_IP_V6.get(&ip).is_none() } {
-                                match unsafe { TCP_IP_V6.insert(&ip, &port, 0) } {
-                                    Ok(_) => {}
-                                    Err(_) => return Err("failed to insert into TCP_IP_V6"),
-                                }
-                            }
-                        }
-                        IpProto::Udp => {
-                            if unsafe { UDP_IP_V6.get(&ip).is_none() } {
-                                match unsafe { UDP_IP_V6.insert(&ip, &port, 0) } {
-                                    Ok(_) => {}
-                                    Err(_) => return Err("failed to insert into UDP_IP_V6"),
-                                }
-                            }
-                        }
-                        _ => {}
-                    }
-                }
+                Ok(unsafe { CUSTOM_PORTS.get(&port).is_some() })
             } else {
-                match ip_proto {
-                    IpProto::Tcp => {
-                        if unsafe { TCP_IP_V6.get(&ip).is_none() } {
-                            match unsafe { TCP_IP_V6.insert(&ip, &port, 0) } {
-                                Ok(_) => {}
-                                Err(_) => return Err("failed to insert into TCP_IP_V6"),
-                            }
-                        }
-                    }
-                    IpProto::Udp => {
-                        if unsafe { UDP_IP_V6.get(&ip).is_none() } {
-                            match unsafe { UDP_IP_V6.insert(&ip, &port, 0) } {
-                                Ok(_) => {}
-                                Err(_) => return Err("failed to insert into UDP_IP_V6"),
-                            }
-                        }
-                    }
-                    _ => {}
-                }
+                Ok(true)
             }
         }
-        None => return Err("failed to get element 0 from USE_CUSTOM_PORTS array"),
+        None => Err("failed to get element 0 from USE_CUSTOM_PORTS array"),
     }
-
-    Ok(())
 }
 
 /// Converts an array of type [u16; 8] (IPv6) from big endian to the target's endianness
@@ -1,26 +1,162 @@
 #![no_std]
 #![no_main]
 
-use aya_bpf::{bindings::xdp_action, macros::xdp, programs::XdpContext};
+use aya_bpf::{
+    bindings::{xdp_action, TC_ACT_PIPE},
+    macros::{classifier, xdp},
+    programs::{TcContext, XdpContext},
+};
 use aya_log_ebpf::info;
 use core::mem::{self, transmute};
 use network_types::{
     eth::{EthHdr, EtherType},
+    icmp::IcmpHdr,
     ip::{IpProto, Ipv4Hdr, Ipv6Hdr},
     tcp::TcpHdr,
     udp::UdpHdr,
+    vxlan::VxlanHdr,
+};
+use xdp_ip_counter_common::{
+    icmp_port, FlowMeta, COUNTER_IPV4_FRAGMENTS, COUNTER_IPV4_OPTIONS, COUNTER_IPV6_EXT_TOO_DEEP,
+    COUNTER_IPV6_EXT_TRUNCATED, COUNTER_IPV6_FRAGMENTS, IPV6_EXT_HEADERS_MAX, TUNNEL_6IN4,
+    TUNNEL_GENEVE, TUNNEL_GRE, TUNNEL_IPIP, TUNNEL_NONE, TUNNEL_VXLAN,
 };
 
 mod ebpf_maps;
 
+/// 802.1Q/802.1ad tag, as it follows the source MAC address in a tagged frame.
+#[repr(C)]
+struct VlanHdr {
+    tci: u16,
+    ether_type: u16,
+}
+impl VlanHdr {
+    const LEN: usize = mem::size_of::<VlanHdr>();
+}
+
+// TPIDs of 802.1Q (customer) and 802.1ad (service) VLAN tags, in network's endianness like EtherType.
+const ETH_P_8021Q: u16 = 0x8100_u16.to_be();
+const ETH_P_8021AD: u16 = 0x88A8_u16.to_be();
+/// Maximum number of stacked VLAN tags to parse (i.e. QinQ).
+const VLAN_TAGS_MAX: usize = 2;
+
+/// Common prefix of IPv6 extension headers (Hop-by-Hop, Routing, Fragment, Destination Options, ...).
+#[repr(C)]
+struct Ipv6ExtHdr {
+    next_hdr: IpProto,
+    hdr_ext_len: u8,
+}
+
+/// IPv6 Fragment extension header.
+#[repr(C)]
+struct Ipv6FragHdr {
+    next_hdr: IpProto,
+    reserved: u8,
+    frag_off: u16,
+    id: u32,
+}
+
+// Fragment offset bits of the IPv4 frag_off field and the IPv6 Fragment header (both in host's endianness).
+const IPV4_FRAG_OFFSET_MASK: u16 = 0x1fff;
+const IPV6_FRAG_OFFSET_MASK: u16 = 0xfff8;
+
+/// Source and destination ports, which SCTP and DCCP headers start with.
+#[repr(C)]
+struct PortsHdr {
+    source: u16,
+    dest: u16,
+}
+
+/// GRE header without the optional checksum, key and sequence number fields.
+#[repr(C)]
+struct GreHdr {
+    flags_version: u16,
+    protocol: u16,
+}
+impl GreHdr {
+    const LEN: usize = mem::size_of::<GreHdr>();
+}
+
+// GRE flags and version bits (in host's endianness).
+const GRE_FLAG_CSUM: u16 = 0x8000;
+const GRE_FLAG_KEY: u16 = 0x2000;
+const GRE_FLAG_SEQ: u16 = 0x1000;
+const GRE_VERSION_MASK: u16 = 0x0007;
+
+/// GENEVE header without the variable length options.
+#[repr(C)]
+struct GeneveHdr {
+    ver_opt_len: u8,
+    flags: u8,
+    protocol: u16,
+    vni: [u8; 3],
+    reserved: u8,
+}
+impl GeneveHdr {
+    const LEN: usize = mem::size_of::<GeneveHdr>();
+}
+
+/// EtherType of Transparent Ethernet Bridging (i.e. the payload is an Ethernet frame), in network's endianness.
+const ETH_P_TEB: u16 = 0x6558_u16.to_be();
+const VXLAN_PORT: u16 = 4789;
+const GENEVE_PORT: u16 = 6081;
+
+/// Tunnel a packet was carried in, see the TUNNEL_* constants.
+struct Tunnel {
+    kind: u8,
+    vni: u32,
+}
+const NO_TUNNEL: Tunnel = Tunnel {
+    kind: TUNNEL_NONE,
+    vni: 0,
+};
+
+/// Remote address of a packet, its source on ingress and its destination on egress, in network's endianness.
+enum RemoteAddr {
+    V4([u8; 4]),
+    V6([u16; 8]),
+}
+
+/// A parsed IP packet, ready to be recorded once its transport header is read.
+struct Packet {
+    addr: RemoteAddr,
+    proto: IpProto,
+    /// Offset of the transport (or tunnel) header.
+    l4_offset: usize,
+}
+
 #[panic_handler]
 fn panic(_info: &core::panic::PanicInfo) -> ! {
     unsafe { core::hint::unreachable_unchecked() }
 }
 
+/// The packet being processed, whichever hook the program is attached to.
+struct PacketCtx {
+    data: usize,
+    data_end: usize,
+    /// Length of the whole packet, which is more than data_end - data when an skb is not linear.
+    len: u64,
+    ifindex: u32,
+    /// Whether the packet is sent rather than received.
+    egress: bool,
+    /// EtherType (in network's endianness) of a packet that starts with its L3 header, as on devices with no L2 header.
+    l3_proto: Option<u16>,
+    /// VLAN ID of a tag the kernel has taken out of the packet data, 0 if none.
+    vlan: u16,
+}
+
 #[xdp(name = "xdp_ip_counter")]
 pub fn xdp_ip_counter(ctx: XdpContext) -> u32 {
-    match try_xdp_ip_counter(&ctx) {
+    let packet = PacketCtx {
+        data: ctx.data(),
+        data_end: ctx.data_end(),
+        len: (ctx.data_end() - ctx.data()) as u64,
+        ifindex: unsafe { (*ctx.ctx).ingress_ifindex },
+        egress: false,
+        l3_proto: None,
+        vlan: 0,
+    };
+    match try_ip_counter(&packet) {
         Ok(_) => xdp_action::XDP_PASS,
         Err(err) => {
             info!(&ctx, "error: {}", err);
@@ -29,63 +165,398 @@ pub fn xdp_ip_counter(ctx: XdpContext) -> u32 {
     }
 }
 
-fn try_xdp_ip_counter<'a>(ctx: &XdpContext) -> Result<(), &'a str> {
-    let ethhdr: *const EthHdr = unsafe { ptr_at(&ctx, 0)? };
-    match unsafe { (*ethhdr).ether_type } {
-        EtherType::Ipv4 => count_v4(&ctx)?,
-        EtherType::Ipv6 => count_v6(&ctx)?,
-        _ => return Ok(()),
+#[classifier(name = "tc_ip_counter_ingress")]
+pub fn tc_ip_counter_ingress(ctx: TcContext) -> i32 {
+    tc_ip_counter(&ctx, false)
+}
+
+#[classifier(name = "tc_ip_counter_egress")]
+pub fn tc_ip_counter_egress(ctx: TcContext) -> i32 {
+    tc_ip_counter(&ctx, true)
+}
+
+/// Counts packets like the XDP program does, with the TC_ACT_PIPE verdict so that the next filters still run.
+/// Only the linear part of the skb is parsed, which holds the headers of all but the most unusual packets.
+#[inline(always)]
+fn tc_ip_counter(ctx: &TcContext, egress: bool) -> i32 {
+    let skb = ctx.skb.skb;
+    // The device the packet is received on at ingress, and sent from at egress.
+    let ifindex = unsafe { (*skb).ifindex };
+    let packet = PacketCtx {
+        data: ctx.data(),
+        data_end: ctx.data_end(),
+        len: ctx.len() as u64,
+        ifindex,
+        egress,
+        // skb->protocol holds the EtherType in network's endianness, which is all there is to tell on L3 devices.
+        l3_proto: match ebpf_maps::is_l3_iface(ifindex) {
+            true => Some(unsafe { (*skb).protocol as u16 }),
+            false => None,
+        },
+        // Tags stripped by the NIC on ingress, or left for it to insert on egress.
+        vlan: match unsafe { (*skb).vlan_present } {
+            0 => 0,
+            _ => unsafe { (*skb).vlan_tci as u16 & 0x0fff },
+        },
+    };
+    match try_ip_counter(&packet) {
+        Ok(_) => TC_ACT_PIPE as i32,
+        Err(err) => {
+            info!(ctx, "error: {}", err);
+            TC_ACT_PIPE as i32
+        }
+    }
+}
+
+fn try_ip_counter<'a>(ctx: &PacketCtx) -> Result<(), &'a str> {
+    let (mut ether_type, mut offset) = match ctx.l3_proto {
+        Some(ether_type) => (ether_type, 0),
+        None => {
+            // EtherType has no variants for VLAN TPIDs, so the field is read as a raw number.
+            let ether_type: *const u16 = unsafe { ptr_at(&ctx, EthHdr::LEN - 2)? };
+            (unsafe { *ether_type }, EthHdr::LEN)
+        }
+    };
+
+    // VLAN ID of the innermost tag, 0 if the frame is untagged.
+    let mut vlan: u16 = ctx.vlan;
+    for _ in 0..VLAN_TAGS_MAX {
+        if ether_type != ETH_P_8021Q && ether_type != ETH_P_8021AD {
+            break;
+        }
+        let vlan_hdr: *const VlanHdr = unsafe { ptr_at(&ctx, offset)? };
+        vlan = u16::from_be(unsafe { (*vlan_hdr).tci }) & 0x0fff;
+        ether_type = unsafe { (*vlan_hdr).ether_type };
+        offset += VlanHdr::LEN;
     }
 
-    Ok(())
+    let packet = match parse_l3(ctx, ether_type, offset)? {
+        Some(packet) => packet,
+        None => return Ok(()),
+    };
+
+    // Only one level of tunnel headers is followed, the inner packet is recorded as-is.
+    if ebpf_maps::decap_enabled()? {
+        if let Some((tunnel, ether_type, offset)) = parse_tunnel(ctx, &packet)? {
+            return match parse_l3(ctx, ether_type, offset)? {
+                Some(inner) => record(ctx, &inner, vlan, &tunnel),
+                None => Ok(()),
+            };
+        }
+    }
+
+    record(ctx, &packet, vlan, &NO_TUNNEL)
+}
+
+/// Parses the IPv4 or IPv6 header at `l3_offset`, `ether_type` being the EtherType (in network's endianness) it was announced with.
+/// Returns None if there is nothing to record, e.g. for non-IP packets and non-first fragments.
+fn parse_l3<'a>(
+    ctx: &PacketCtx,
+    ether_type: u16,
+    l3_offset: usize,
+) -> Result<Option<Packet>, &'a str> {
+    if ether_type == EtherType::Ipv4 as u16 {
+        parse_v4(ctx, l3_offset)
+    } else if ether_type == EtherType::Ipv6 as u16 {
+        parse_v6(ctx, l3_offset)
+    } else {
+        Ok(None)
+    }
+}
+
+fn parse_v4<'a>(ctx: &PacketCtx, l3_offset: usize) -> Result<Option<Packet>, &'a str> {
+    let ipv4_hdr: *const Ipv4Hdr = unsafe { ptr_at(&ctx, l3_offset)? };
+    let addr: [u8; 4] = match ctx.egress {
+        true => unsafe { transmute((*ipv4_hdr).dst_addr) },
+        false => unsafe { transmute((*ipv4_hdr).src_addr) },
+    };
+
+    // IHL is the lower nibble of the first byte and holds the header length in 32-bit words.
+    // Masking it keeps the L4 offset bounded (at most 60 bytes) so the verifier can check the access.
+    let ihl = (unsafe { *(ipv4_hdr as *const u8) } & 0x0f) as usize;
+    if ihl < 5 {
+        return Err("invalid IPv4 header length");
+    }
+    if ihl > 5 {
+        ebpf_maps::increment_counter(COUNTER_IPV4_OPTIONS);
+    }
+    let l4_offset = l3_offset + (ihl << 2);
+
+    // Non-first fragments carry no transport header, so there is no port to attribute them to.
+    if u16::from_be(unsafe { (*ipv4_hdr).frag_off }) & IPV4_FRAG_OFFSET_MASK != 0 {
+        ebpf_maps::increment_counter(COUNTER_IPV4_FRAGMENTS);
+        return Ok(None);
+    }
+
+    Ok(Some(Packet {
+        addr: RemoteAddr::V4(addr),
+        proto: unsafe { (*ipv4_hdr).proto },
+        l4_offset,
+    }))
+}
+
+fn parse_v6<'a>(ctx: &PacketCtx, l3_offset: usize) -> Result<Option<Packet>, &'a str> {
+    let ipv6_hdr: *const Ipv6Hdr = unsafe { ptr_at(&ctx, l3_offset)? };
+    let addr = match ctx.egress {
+        true => unsafe { (*ipv6_hdr).dst_addr.in6_u.u6_addr16 },
+        false => unsafe { (*ipv6_hdr).src_addr.in6_u.u6_addr16 },
+    };
+
+    let (next_hdr, l4_offset) = match skip_ipv6_ext_headers(
+        ctx,
+        unsafe { (*ipv6_hdr).next_hdr },
+        l3_offset + Ipv6Hdr::LEN,
+    )? {
+        Some(upper_layer) => upper_layer,
+        // Non-first fragment, there is no transport header to read the port from.
+        None => return Ok(None),
+    };
+
+    Ok(Some(Packet {
+        addr: RemoteAddr::V6(addr),
+        proto: next_hdr,
+        l4_offset,
+    }))
 }
 
-fn count_v4<'a>(ctx: &XdpContext) -> Result<(), &'a str> {
-    let ipv4_hdr: *const Ipv4Hdr = unsafe { ptr_at(&ctx, EthHdr::LEN)? };
-    let source_addr: [u8; 4] = unsafe { transmute((*ipv4_hdr).src_addr) };
+/// Reads the destination port (or ICMP type and code) of `packet` and records it in the ebpf maps.
+fn record<'a>(ctx: &PacketCtx, packet: &Packet, vlan: u16, tunnel: &Tunnel) -> Result<(), &'a str> {
+    let bytes = ctx.len;
+    let ifindex = ctx.ifindex;
+
+    match packet.proto {
+        IpProto::Tcp | IpProto::Udp | IpProto::Sctp | IpProto::Dccp => {}
+        IpProto::Icmp | IpProto::Ipv6Icmp => {
+            // ICMP and ICMPv6 share the layout of the type and code fields.
+            let icmphdr: *const IcmpHdr = unsafe { ptr_at(&ctx, packet.l4_offset) }?;
+            let meta = FlowMeta::new(
+                ifindex,
+                packet.proto as u8,
+                icmp_port(unsafe { (*icmphdr).type_ }, unsafe { (*icmphdr).code }),
+                vlan,
+                tunnel.kind,
+                tunnel.vni,
+            );
+            return match packet.addr {
+                RemoteAddr::V4(ref addr) => ebpf_maps::add_icmp_v4(addr, meta, bytes),
+                RemoteAddr::V6(ref addr) => ebpf_maps::add_icmp_v6(addr, meta, bytes),
+            };
+        }
+        _ => return Err("only TCP, UDP, SCTP, DCCP, ICMP and ICMPv6 are supported"),
+    }
 
-    match unsafe { (*ipv4_hdr).proto } {
+    let port = match packet.proto {
         IpProto::Tcp => {
-            let tcphdr: *const TcpHdr = unsafe { ptr_at(&ctx, EthHdr::LEN + Ipv4Hdr::LEN) }?;
-            let port = unsafe { (*tcphdr).dest };
-            ebpf_maps::add_v4(IpProto::Tcp, &source_addr, &port)?;
+            let tcphdr: *const TcpHdr = unsafe { ptr_at(&ctx, packet.l4_offset) }?;
+            unsafe { (*tcphdr).dest }
         }
         IpProto::Udp => {
-            let udphdr: *const UdpHdr = unsafe { ptr_at(&ctx, EthHdr::LEN + Ipv4Hdr::LEN) }?;
-            let port = unsafe { (*udphdr).dest };
-            ebpf_maps::add_v4(IpProto::Udp, &source_addr, &port)?;
+            let udphdr: *const UdpHdr = unsafe { ptr_at(&ctx, packet.l4_offset) }?;
+            unsafe { (*udphdr).dest }
+        }
+        // SCTP and DCCP
+        _ => {
+            let portshdr: *const PortsHdr = unsafe { ptr_at(&ctx, packet.l4_offset) }?;
+            unsafe { (*portshdr).dest }
         }
-        _ => return Err("only TCP and UDP are supported"),
     };
 
-    Ok(())
+    // Converting Port from Network's endianness to host's endianness
+    let meta = FlowMeta::new(
+        ifindex,
+        packet.proto as u8,
+        u16::from_be(port),
+        vlan,
+        tunnel.kind,
+        tunnel.vni,
+    );
+
+    if ebpf_maps::hll_enabled()? {
+        return match packet.addr {
+            RemoteAddr::V4(ref addr) => ebpf_maps::add_hll_v4(addr, meta),
+            RemoteAddr::V6(ref addr) => ebpf_maps::add_hll_v6(addr, meta),
+        };
+    }
+
+    match packet.addr {
+        RemoteAddr::V4(ref addr) => ebpf_maps::add_v4(addr, meta, bytes),
+        RemoteAddr::V6(ref addr) => ebpf_maps::add_v6(addr, meta, bytes),
+    }
 }
 
-fn count_v6<'a>(ctx: &XdpContext) -> Result<(), &'a str> {
-    let ipv6_hdr: *const Ipv6Hdr = unsafe { ptr_at(&ctx, EthHdr::LEN)? };
-    let src_addr = unsafe { (*ipv6_hdr).src_addr.in6_u.u6_addr16 };
+/// Returns the tunnel `packet` is part of along with the EtherType (in network's endianness) and offset of the inner L3 header.
+/// Returns None if `packet` is not a (supported) tunnel packet.
+fn parse_tunnel<'a>(
+    ctx: &PacketCtx,
+    packet: &Packet,
+) -> Result<Option<(Tunnel, u16, usize)>, &'a str> {
+    let offset = packet.l4_offset;
 
-    match unsafe { (*ipv6_hdr).next_hdr } {
-        IpProto::Tcp => {
-            let tcphdr: *const TcpHdr = unsafe { ptr_at(&ctx, EthHdr::LEN + Ipv6Hdr::LEN) }?;
-            let port = unsafe { (*tcphdr).dest };
-            ebpf_maps::add_v6(IpProto::Tcp, &src_addr, &port)?;
+    match packet.proto {
+        IpProto::Ipv4 => Ok(Some((
+            Tunnel {
+                kind: TUNNEL_IPIP,
+                vni: 0,
+            },
+            EtherType::Ipv4 as u16,
+            offset,
+        ))),
+        IpProto::Ipv6 => Ok(Some((
+            Tunnel {
+                kind: TUNNEL_6IN4,
+                vni: 0,
+            },
+            EtherType::Ipv6 as u16,
+            offset,
+        ))),
+        IpProto::Gre => {
+            let gre_hdr: *const GreHdr = unsafe { ptr_at(&ctx, offset)? };
+            let flags = u16::from_be(unsafe { (*gre_hdr).flags_version });
+            // Version 1 is PPTP's enhanced GRE, which carries PPP rather than IP.
+            if flags & GRE_VERSION_MASK != 0 {
+                return Ok(None);
+            }
+
+            let mut offset = offset + GreHdr::LEN;
+            if flags & GRE_FLAG_CSUM != 0 {
+                offset += 4;
+            }
+            let mut vni = 0;
+            if flags & GRE_FLAG_KEY != 0 {
+                let key: *const u32 = unsafe { ptr_at(&ctx, offset)? };
+                vni = u32::from_be(unsafe { *key });
+                offset += 4;
+            }
+            if flags & GRE_FLAG_SEQ != 0 {
+                offset += 4;
+            }
+
+            let tunnel = Tunnel {
+                kind: TUNNEL_GRE,
+                vni,
+            };
+            tunnel_payload(ctx, tunnel, unsafe { (*gre_hdr).protocol }, offset)
         }
         IpProto::Udp => {
-            let udphdr: *const UdpHdr = unsafe { ptr_at(&ctx, EthHdr::LEN + Ipv6Hdr::LEN) }?;
-            let port = unsafe { (*udphdr).dest };
-            ebpf_maps::add_v6(IpProto::Udp, &src_addr, &port)?;
+            let udp_hdr: *const UdpHdr = unsafe { ptr_at(&ctx, offset)? };
+            let offset = offset + UdpHdr::LEN;
+
+            match u16::from_be(unsafe { (*udp_hdr).dest }) {
+                VXLAN_PORT => {
+                    let vxlan_hdr: *const VxlanHdr = unsafe { ptr_at(&ctx, offset)? };
+                    let tunnel = Tunnel {
+                        kind: TUNNEL_VXLAN,
+                        vni: unsafe { (*vxlan_hdr).vni() },
+                    };
+                    tunnel_payload(ctx, tunnel, ETH_P_TEB, offset + VxlanHdr::LEN)
+                }
+                GENEVE_PORT => {
+                    let geneve_hdr: *const GeneveHdr = unsafe { ptr_at(&ctx, offset)? };
+                    // Options length is in 4-octet units.
+                    let opt_len = ((unsafe { (*geneve_hdr).ver_opt_len } & 0x3f) as usize) << 2;
+                    let vni = unsafe { (*geneve_hdr).vni };
+                    let tunnel = Tunnel {
+                        kind: TUNNEL_GENEVE,
+                        vni: (vni[0] as u32) << 16 | (vni[1] as u32) << 8 | vni[2] as u32,
+                    };
+                    tunnel_payload(
+                        ctx,
+                        tunnel,
+                        unsafe { (*geneve_hdr).protocol },
+                        offset + GeneveHdr::LEN + opt_len,
+                    )
+                }
+                _ => Ok(None),
+            }
         }
-        _ => return Err("only TCP and UDP are supported"),
+        _ => Ok(None),
+    }
+}
+
+/// Locates the inner L3 header of a tunnel whose payload starts at `offset` and is of type `protocol` (an EtherType in network's endianness).
+fn tunnel_payload<'a>(
+    ctx: &PacketCtx,
+    tunnel: Tunnel,
+    protocol: u16,
+    offset: usize,
+) -> Result<Option<(Tunnel, u16, usize)>, &'a str> {
+    if protocol == ETH_P_TEB {
+        // The payload is a whole Ethernet frame, VLAN tags of the inner frame are not parsed.
+        let ether_type: *const u16 = unsafe { ptr_at(&ctx, offset + EthHdr::LEN - 2)? };
+        return Ok(Some((tunnel, unsafe { *ether_type }, offset + EthHdr::LEN)));
+    }
+
+    Ok(Some((tunnel, protocol, offset)))
+}
+
+/// Walks the IPv6 extension header chain that starts at `offset` with header type `next_hdr`
+/// and returns the upper-layer protocol along with the offset of its header.
+/// Returns None if the packet is a non-first fragment, which has no upper-layer header.
+fn skip_ipv6_ext_headers<'a>(
+    ctx: &PacketCtx,
+    mut next_hdr: IpProto,
+    mut offset: usize,
+) -> Result<Option<(IpProto, usize)>, &'a str> {
+    let max_depth = ebpf_maps::ipv6_ext_max_depth()?;
+
+    // The loop is bounded by a constant to keep the verifier happy, the configured depth is checked inside.
+    for depth in 0..=IPV6_EXT_HEADERS_MAX {
+        match next_hdr {
+            IpProto::HopOpt
+            | IpProto::Ipv6Route
+            | IpProto::Ipv6Frag
+            | IpProto::Ipv6Opts
+            | IpProto::Ah
+            | IpProto::MobilityHeader => {}
+            _ => return Ok(Some((next_hdr, offset))),
+        }
+        if depth >= max_depth {
+            break;
+        }
+
+        let ext_hdr: *const Ipv6ExtHdr = match unsafe { ptr_at(&ctx, offset) } {
+            Ok(ext_hdr) => ext_hdr,
+            Err(err) => {
+                ebpf_maps::increment_counter(COUNTER_IPV6_EXT_TRUNCATED);
+                return Err(err);
+            }
+        };
+        if next_hdr == IpProto::Ipv6Frag {
+            let frag_hdr: *const Ipv6FragHdr = match unsafe { ptr_at(&ctx, offset) } {
+                Ok(frag_hdr) => frag_hdr,
+                Err(err) => {
+                    ebpf_maps::increment_counter(COUNTER_IPV6_EXT_TRUNCATED);
+                    return Err(err);
+                }
+            };
+            if u16::from_be(unsafe { (*frag_hdr).frag_off }) & IPV6_FRAG_OFFSET_MASK != 0 {
+                ebpf_maps::increment_counter(COUNTER_IPV6_FRAGMENTS);
+                return Ok(None);
+            }
+        }
+
+        let hdr_ext_len = unsafe { (*ext_hdr).hdr_ext_len } as usize;
+        let len = match next_hdr {
+            // Fragment header has a fixed size, its second byte is reserved.
+            IpProto::Ipv6Frag => 8,
+            // AH's length is in 4-octet units, not counting the first 2 units.
+            IpProto::Ah => (hdr_ext_len + 2) << 2,
+            // Everything else is in 8-octet units, not counting the first 8 octets.
+            _ => (hdr_ext_len + 1) << 3,
+        };
+
+        next_hdr = unsafe { (*ext_hdr).next_hdr };
+        offset += len;
     }
 
-    Ok(())
+    ebpf_maps::increment_counter(COUNTER_IPV6_EXT_TOO_DEEP);
+    Err("IPv6 extension header chain exceeds the maximum depth")
 }
 
 #[inline(always)]
-unsafe fn ptr_at<'a, T>(ctx: &XdpContext, offset: usize) -> Result<*const T, &'a str> {
-    let start = ctx.data();
-    let end = ctx.data_end();
+unsafe fn ptr_at<'a, T>(ctx: &PacketCtx, offset: usize) -> Result<*const T, &'a str> {
+    let start = ctx.data;
+    let end = ctx.data_end;
     let len = mem::size_of::<T>();
 
     if start + offset + len > end {
@@ -1,13 +1,13 @@
 use std::{
+    collections::{BTreeMap, HashMap},
     fmt::Write,
     sync::{Arc, RwLock},
 };
 
-use crate::structs::{L4Proto, LocalMap};
+use crate::structs::{IpItem, L4Proto, LocalMap, Traffic, COUNTERS};
 
 pub fn generate_mertics(local_map: Arc<RwLock<LocalMap>>) -> Result<String, std::fmt::Error> {
-    let prom_metrics = local_map.read().unwrap();
-    let prom_metrics = prom_metrics.get_prom_metrics();
+    let local_map = local_map.read().unwrap();
     let mut metrics_buffer = String::new();
 
     metrics_buffer
@@ -15,26 +15,184 @@ pub fn generate_mertics(local_map: Arc<RwLock<LocalMap>>) -> Result<String, std:
 
     metrics_buffer.write_str("# TYPE active_users counter\n")?;
 
-    for (l3, l4_ips) in prom_metrics.iter() {
-        for (l4, ips) in l4_ips {
-            let port = match l4 {
-                L4Proto::Tcp(port) => port,
-                L4Proto::Udp(port) => port,
-            };
+    for window in local_map.get_windows() {
+        for (l3, l4_ips) in window.get_ips().iter() {
+            for (l4, ips) in l4_ips {
+                let port = match l4.port() {
+                    Some(port) => port,
+                    None => continue,
+                };
+                for (labels, count) in count_per_encap(ips) {
+                    metrics_buffer.write_str(
+                        format!(
+                            "active_users{{network=\"{}\",transport=\"{}\",port=\"{}\"{},window=\"{}\"}} {}\n",
+                            l3, l4, port, labels, window.label(), count
+                        )
+                        .as_str(),
+                    )?;
+                }
+            }
+        }
+
+        // HLL mode, sorted so series keep the same order between scrapes
+        let estimates: BTreeMap<String, f64> = window
+            .get_sketches()
+            .iter()
+            .filter_map(|((l3, l4, encap), hll)| {
+                let port = l4.port()?;
+                Some((
+                    format!(
+                        "network=\"{}\",transport=\"{}\",port=\"{}\"{}",
+                        l3,
+                        l4,
+                        port,
+                        encap.labels()
+                    ),
+                    hll.estimate(),
+                ))
+            })
+            .collect();
+        for (labels, estimate) in estimates {
             metrics_buffer.write_str(
                 format!(
-                    "active_users{{network=\"{}\",transport=\"{}\",port=\"{}\"}} {}\n",
-                    l3,
-                    l4,
-                    port,
-                    ips.len()
+                    "active_users{{{},window=\"{}\"}} {}\n",
+                    labels,
+                    window.label(),
+                    estimate.round()
                 )
                 .as_str(),
             )?;
         }
     }
 
+    metrics_buffer.write_str(
+        "# HELP active_icmp_users Number of users actively sending a specific ICMP type and code.\n",
+    )?;
+
+    metrics_buffer.write_str("# TYPE active_icmp_users gauge\n")?;
+
+    for window in local_map.get_windows() {
+        for (l3, l4_ips) in window.get_ips().iter() {
+            for (l4, ips) in l4_ips {
+                let (icmp_type, code) = match l4.icmp() {
+                    Some(icmp) => icmp,
+                    None => continue,
+                };
+                for (labels, count) in count_per_encap(ips) {
+                    metrics_buffer.write_str(
+                        format!(
+                            "active_icmp_users{{network=\"{}\",transport=\"{}\",type=\"{}\",code=\"{}\"{},window=\"{}\"}} {}\n",
+                            l3, l4, icmp_type, code, labels, window.label(), count
+                        )
+                        .as_str(),
+                    )?;
+                }
+            }
+        }
+    }
+
+    // Sorted by labels so series keep the same order between scrapes
+    let totals: BTreeMap<String, &Traffic> = local_map
+        .get_totals()
+        .iter()
+        .map(|((l3, l4, encap), traffic)| {
+            (
+                format!(
+                    "network=\"{}\",transport=\"{}\",{}{}",
+                    l3,
+                    l4,
+                    l4_labels(l4),
+                    encap.labels()
+                ),
+                traffic,
+            )
+        })
+        .collect();
+
+    metrics_buffer.write_str("# HELP packets_total Number of packets seen per protocol.\n")?;
+    metrics_buffer.write_str("# TYPE packets_total counter\n")?;
+    for (labels, traffic) in totals.iter() {
+        metrics_buffer
+            .write_str(format!("packets_total{{{}}} {}\n", labels, traffic.packets).as_str())?;
+    }
+
+    metrics_buffer
+        .write_str("# HELP bytes_total Number of bytes seen per protocol, L2 header included.\n")?;
+    metrics_buffer.write_str("# TYPE bytes_total counter\n")?;
+    for (labels, traffic) in totals.iter() {
+        metrics_buffer
+            .write_str(format!("bytes_total{{{}}} {}\n", labels, traffic.bytes).as_str())?;
+    }
+
+    let mut last_name = "";
+    for counter in COUNTERS {
+        if counter.name != last_name {
+            last_name = counter.name;
+            metrics_buffer
+                .write_str(format!("# HELP {} {}\n", counter.name, counter.help).as_str())?;
+            metrics_buffer.write_str(format!("# TYPE {} counter\n", counter.name).as_str())?;
+        }
+        let labels = match counter.labels {
+            "" => String::new(),
+            labels => format!("{{{}}}", labels),
+        };
+        metrics_buffer.write_str(
+            format!(
+                "{}{} {}\n",
+                counter.name,
+                labels,
+                local_map.get_counter(counter.index)
+            )
+            .as_str(),
+        )?;
+    }
+
+    metrics_buffer
+        .write_str("# HELP xdp_mode_info Mode the XDP program is attached in per interface.\n")?;
+    metrics_buffer.write_str("# TYPE xdp_mode_info gauge\n")?;
+    for (iface, mode) in local_map.get_xdp_modes() {
+        metrics_buffer.write_str(
+            format!("xdp_mode_info{{iface=\"{}\",mode=\"{}\"}} 1\n", iface, mode).as_str(),
+        )?;
+    }
+
+    metrics_buffer.write_str(
+        "# HELP iface_events_total Interfaces matching --iface that appeared or disappeared after startup.\n",
+    )?;
+    metrics_buffer.write_str("# TYPE iface_events_total counter\n")?;
+    for (event, count) in local_map.get_iface_events() {
+        metrics_buffer
+            .write_str(format!("iface_events_total{{event=\"{}\"}} {}\n", event, count).as_str())?;
+    }
+
+    metrics_buffer.write_str(
+        "# HELP map_full_samples_total Number of samples in which the ebpf map was full, dropping new flows or with --lru evicting old ones.\n",
+    )?;
+    metrics_buffer.write_str("# TYPE map_full_samples_total counter\n")?;
+    for (map, count) in local_map.get_full_maps() {
+        metrics_buffer
+            .write_str(format!("map_full_samples_total{{map=\"{}\"}} {}\n", map, count).as_str())?;
+    }
+
     metrics_buffer.write_str("# EOF\n")?;
 
     Ok(metrics_buffer)
 }
+
+/// Counts IPs per VLAN/tunnel so each gets its own series, plain traffic goes without these labels.
+fn count_per_encap(ips: &HashMap<IpItem, Traffic>) -> BTreeMap<String, usize> {
+    let mut per_encap: BTreeMap<String, usize> = BTreeMap::new();
+    for ip in ips.keys() {
+        *per_encap.entry(ip.encap().labels()).or_default() += 1;
+    }
+    per_encap
+}
+
+/// Returns the labels identifying what `l4` was sent to: a port, or an ICMP type and code.
+fn l4_labels(l4: &L4Proto) -> String {
+    match (l4.port(), l4.icmp()) {
+        (Some(port), _) => format!("port=\"{}\"", port),
+        (None, Some((icmp_type, code))) => format!("type=\"{}\",code=\"{}\"", icmp_type, code),
+        (None, None) => String::new(),
+    }
+}
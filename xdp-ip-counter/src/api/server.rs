@@ -1,38 +1,84 @@
-use std::sync::{Arc, RwLock};
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex, RwLock},
+    time::SystemTime,
+};
 use warp::reply::json;
 use warp::{http, Filter};
 
 use crate::api::prometheus;
+use crate::storage::{self, Storage};
 use crate::structs::LocalMap;
 
-pub async fn serve(local_map: Arc<RwLock<LocalMap>>, server_port: u16, serve_ip_list: bool) {
+pub async fn serve(
+    local_map: Arc<RwLock<LocalMap>>,
+    storage: Option<Arc<Mutex<Storage>>>,
+    server_port: u16,
+    serve_ip_list: bool,
+) {
     let lm1 = local_map.clone();
     let lm2 = local_map.clone();
+    let lm3 = local_map.clone();
+    let st1 = storage.clone();
+    let st2 = storage.clone();
 
     let metrics_route = warp::get()
         .and(warp::path("metrics"))
         .and(warp::any().map(move || lm1.clone()))
         .and_then(prometheus_metrics);
 
+    let history_route = warp::get()
+        .and(warp::path("history"))
+        .and(warp::query::<HashMap<String, String>>())
+        .and(warp::any().map(move || lm3.clone()))
+        .and_then(history);
+
+    let query_route = warp::get()
+        .and(warp::path("query"))
+        .and(warp::query::<HashMap<String, String>>())
+        .and(warp::any().map(move || st1.clone()))
+        .and_then(query);
+
+    let ip_route = warp::get()
+        .and(warp::path!("ip" / String))
+        .and(warp::query::<HashMap<String, String>>())
+        .and(warp::any().map(move || st2.clone()))
+        .and_then(ip_activity);
+
     println!(
         "Prometheus metrics endpoint: http://0.0.0.0:{}/metrics",
         server_port
     );
+    println!("History endpoint: http://0.0.0.0:{}/history", server_port);
+    // Persisted IPs are served along with the IP list, under the same opt-in
     match serve_ip_list {
         true => {
             let ips_route = warp::get()
                 .and(warp::path("list"))
+                .and(warp::query::<HashMap<String, String>>())
                 .and(warp::any().map(move || lm2.clone()))
                 .and_then(ip_data_list);
 
             println!("IP list endpoint: http://0.0.0.0:{}/list", server_port);
-            let routes = warp::get().and(metrics_route.or(ips_route));
+            if storage.is_some() {
+                println!(
+                    "Query endpoints: http://0.0.0.0:{0}/query, http://0.0.0.0:{0}/ip/<addr>",
+                    server_port
+                );
+            }
+            let routes = warp::get().and(
+                metrics_route
+                    .or(history_route)
+                    .or(ips_route)
+                    .or(query_route)
+                    .or(ip_route),
+            );
             warp::serve(routes).run(([0, 0, 0, 0], server_port)).await;
         }
         false => {
-            warp::serve(metrics_route)
-                .run(([0, 0, 0, 0], server_port))
-                .await;
+            let routes = warp::get().and(metrics_route.or(history_route));
+            warp::serve(routes).run(([0, 0, 0, 0], server_port)).await;
         }
     }
 }
@@ -53,12 +99,22 @@ async fn prometheus_metrics(
 }
 
 async fn ip_data_list(
+    query: HashMap<String, String>,
     local_map: Arc<RwLock<LocalMap>>,
 ) -> Result<impl warp::Reply, warp::Rejection> {
     let local_map = local_map
         .read()
         .expect("unable to accuire lock for local_map");
-    let ip_list = local_map.get_ip_list();
+    // e.g. /list?window=5m, defaults to the first --window
+    let ip_list = match local_map.get_ip_list(query.get("window").map(String::as_str)) {
+        Some(ip_list) => ip_list,
+        None => {
+            return Ok(warp::reply::with_status(
+                json(&"Unknown window"),
+                http::StatusCode::NOT_FOUND,
+            ))
+        }
+    };
 
     // Ok(warp::reply::with_header(
     //     warp::reply::with_status(json(&ip_list), http::StatusCode::OK),
@@ -71,3 +127,126 @@ async fn ip_data_list(
         http::StatusCode::OK,
     ))
 }
+
+/// Serves summaries of past windows, e.g. /history?port=22&from=2023-05-02T10:00:00Z&to=2023-05-02T11:00:00Z
+async fn history(
+    query: HashMap<String, String>,
+    local_map: Arc<RwLock<LocalMap>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let (from, to, port) = match (
+        parse_time(query.get("from")),
+        parse_time(query.get("to")),
+        query
+            .get("port")
+            .map(|port| port.parse::<u16>())
+            .transpose(),
+    ) {
+        (Ok(from), Ok(to), Ok(port)) => (from, to, port),
+        _ => {
+            return Ok(warp::reply::with_status(
+                json(&"from and to must be RFC 3339 timestamps and port a port number"),
+                http::StatusCode::BAD_REQUEST,
+            ))
+        }
+    };
+
+    let local_map = local_map
+        .read()
+        .expect("unable to accuire lock for local_map");
+    let history = local_map.get_history(from, to, port);
+
+    Ok(warp::reply::with_status(
+        json(&history),
+        http::StatusCode::OK,
+    ))
+}
+
+/// Serves persisted IPs that sent traffic to a port, e.g. /query?port=5432&from=2023-05-02T10:00:00Z&to=2023-05-02T11:00:00Z
+async fn query(
+    query: HashMap<String, String>,
+    storage: Option<Arc<Mutex<Storage>>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let port = match query.get("port").map(|port| port.parse::<u16>()) {
+        Some(Ok(port)) => port,
+        _ => {
+            return Ok(warp::reply::with_status(
+                json(&"port must be a port number"),
+                http::StatusCode::BAD_REQUEST,
+            ))
+        }
+    };
+    stored_ips(storage, &query, None, Some(port)).await
+}
+
+/// Serves every persisted port an address has sent traffic to, e.g. /ip/10.0.0.1?window=5m
+async fn ip_activity(
+    ip: String,
+    query: HashMap<String, String>,
+    storage: Option<Arc<Mutex<Storage>>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let ip = match ip.parse::<IpAddr>() {
+        Ok(ip) => ip,
+        Err(_) => {
+            return Ok(warp::reply::with_status(
+                json(&"Invalid IP address"),
+                http::StatusCode::BAD_REQUEST,
+            ))
+        }
+    };
+    stored_ips(storage, &query, Some(ip), None).await
+}
+
+async fn stored_ips(
+    storage: Option<Arc<Mutex<Storage>>>,
+    query: &HashMap<String, String>,
+    ip: Option<IpAddr>,
+    port: Option<u16>,
+) -> Result<warp::reply::WithStatus<warp::reply::Json>, warp::Rejection> {
+    let storage = match storage {
+        Some(storage) => storage,
+        None => {
+            return Ok(warp::reply::with_status(
+                json(&"No database configured, see --db"),
+                http::StatusCode::NOT_FOUND,
+            ))
+        }
+    };
+    let (from, to) = match (parse_time(query.get("from")), parse_time(query.get("to"))) {
+        (Ok(from), Ok(to)) => (from, to),
+        _ => {
+            return Ok(warp::reply::with_status(
+                json(&"from and to must be RFC 3339 timestamps"),
+                http::StatusCode::BAD_REQUEST,
+            ))
+        }
+    };
+    let filter = storage::Filter {
+        ip,
+        port,
+        window: query.get("window").cloned(),
+        from,
+        to,
+    };
+
+    // Querying SQLite blocks, possibly behind a window being persisted
+    let rows = tokio::task::spawn_blocking(move || {
+        storage
+            .lock()
+            .expect("unable to accuire lock for storage")
+            .query(&filter)
+    })
+    .await;
+    match rows {
+        Ok(Ok(rows)) => Ok(warp::reply::with_status(json(&rows), http::StatusCode::OK)),
+        _ => Ok(warp::reply::with_status(
+            json(&"Internal Server Error"),
+            http::StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
+
+fn parse_time(value: Option<&String>) -> Result<Option<SystemTime>, humantime::TimestampError> {
+    value
+        .map(|value| humantime::parse_rfc3339_weak(value))
+        .transpose()
+}
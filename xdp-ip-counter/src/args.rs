@@ -1,4 +1,8 @@
 use clap::{ArgAction, Parser};
+use std::time::Duration;
+use xdp_ip_counter_common::IPV6_EXT_HEADERS_MAX;
+
+use crate::structs::{window_label, Hook, XdpMode};
 
 /// An eBPF XDP program that helps with finding IP addresses that have tried to reach out to a specific port during a certain interval.
 /// Metrics are served in prometheus format on :[server_port]/metrics and
@@ -7,24 +11,96 @@ use clap::{ArgAction, Parser};
 #[command(version)]
 pub struct Args {
     #[clap(short, long, default_value = "eth0")]
-    /// Network Interface to attach eBPF program to.
+    /// Comma-separated network interfaces to attach eBPF program to. Glob patterns such as veth* are accepted.
     pub iface: String,
 
+    #[clap(long, default_value = "xdp")]
+    /// Where to attach the eBPF program: xdp, tc-ingress or tc-egress (TC classifiers on the clsact qdisc). tc-egress counts the destinations of sent packets instead of the sources of received ones.
+    pub hook: String,
+
+    #[clap(long, default_value = "skb")]
+    /// Mode to attach the XDP program in: skb (generic), drv (native), hw (offloaded) or auto (drv, falling back to skb).
+    pub xdp_mode: String,
+
     #[clap(short, long, default_value = "0")]
     /// Comma-separated ports to collect data for. 0 means all ports.
     pub ports: String,
 
     #[clap(short, long, default_value = "60")]
-    /// Sampling interval in seconds. value must be divisable by 10.
+    /// Comma-separated aggregation windows in seconds, e.g. 60,300,3600. values must be multiples of the sampling interval.
     pub window: String,
 
+    #[clap(long, action=ArgAction::SetTrue)]
+    /// Whether windows slide, always covering the trailing window updated every sampling interval, instead of being published once complete.
+    pub sliding: bool,
+
+    #[clap(long, default_value = "10")]
+    /// Interval in seconds at which eBPF maps are read and cleared.
+    pub sampling_interval: String,
+
+    #[clap(long, default_value = "10000")]
+    /// Maximum number of per-port summaries of past windows to keep in memory and serve on :[server_port]/history
+    pub history_size: String,
+
+    #[clap(long)]
+    /// Path of a SQLite database to persist the IPs of each completed window to. Nothing is persisted if not set.
+    pub db: Option<String>,
+
+    #[clap(long, default_value = "30")]
+    /// Number of days persisted IPs are kept in the database for.
+    pub db_retention: String,
+
+    #[clap(long)]
+    /// Path of a file the windows are saved to periodically and on exit, and restored from on startup. Nothing is saved if not set.
+    pub snapshot: Option<String>,
+
+    #[clap(long, default_value = "60")]
+    /// Interval in seconds at which the snapshot file is written.
+    pub snapshot_interval: String,
+
+    #[clap(long, default_value = "600")]
+    /// Maximum age in seconds of a snapshot for it to be restored on startup.
+    pub snapshot_max_age: String,
+
+    #[clap(long, default_value = "3600")]
+    /// Seconds after which the packets_total and bytes_total series of a protocol, port and encapsulation no longer seen are dropped.
+    pub totals_max_idle: String,
+
     #[clap(short, long, default_value = "3031")]
     /// Port to serve prometheus metrics on (i.e. HTTP Server Port)
     pub server_port: String,
 
     #[clap(long, action=ArgAction::SetTrue)]
-    /// Whether to serve a list of connected IP addresses on :[server_port]/list
+    /// Whether to serve a list of connected IP addresses on :[server_port]/list, and persisted ones on :[server_port]/query and :[server_port]/ip/<addr> with --db
     pub serve_ip_list: bool,
+
+    #[clap(long, action=ArgAction::SetTrue)]
+    /// Whether to decapsulate GRE, IPIP, 6in4, VXLAN and GENEVE tunnels (one level) and count inner flows instead
+    pub decap: bool,
+
+    #[clap(long, default_value = "4")]
+    /// Maximum number of IPv6 extension headers to walk through before giving up on a packet (up to 8).
+    pub ipv6_ext_max_depth: String,
+
+    #[clap(long, default_value = "10240")]
+    /// Maximum number of flows (source, protocol, port) each eBPF map can hold per sampling interval.
+    pub map_capacity: String,
+
+    #[clap(long, action=ArgAction::SetTrue)]
+    /// Whether to use LRU maps, which evict the least recently seen flows instead of dropping new ones when full. Evicted flows are lost without being counted as insert failures, samples in which a map filled up are counted in map_full_samples_total.
+    pub lru: bool,
+
+    #[clap(long, action=ArgAction::SetTrue)]
+    /// Whether to estimate active users per port with HyperLogLog sketches (about 3.25% standard error) instead of tracking each source. The IP list then only holds ICMP sources.
+    pub hll: bool,
+
+    #[clap(long, default_value = "1024")]
+    /// Maximum number of HyperLogLog sketches (network, protocol, port, interface) each eBPF map can hold per sampling interval with --hll.
+    pub hll_capacity: String,
+
+    #[clap(long, action=ArgAction::SetTrue)]
+    /// Whether to pin the program, its link and maps under /sys/fs/bpf/xdp-ip-counter/ so that they survive restarts, reusing them if already pinned.
+    pub pin: bool,
 }
 
 impl Args {
@@ -53,18 +129,181 @@ impl Args {
         }
     }
 
-    pub fn parse_window(&self) -> u64 {
-        let window = self
+    pub fn parse_windows(&self) -> Vec<u64> {
+        let sampling_interval = self.parse_sampling_interval();
+        let windows: Vec<u64> = self
             .window
             .trim()
+            .split(',')
+            .map(|window| {
+                let window = window.trim().parse::<u64>().expect(
+                    "windows must be positive integers and multiples of the sampling interval",
+                );
+                if window == 0 || window % sampling_interval != 0 {
+                    panic!(
+                        "windows must be positive integers and multiples of the sampling interval ({})",
+                        sampling_interval
+                    );
+                }
+                window
+            })
+            .collect();
+
+        let mut labels: Vec<String> = windows.iter().map(|w| window_label(*w)).collect();
+        labels.sort();
+        labels.dedup();
+        if labels.len() != windows.len() {
+            panic!("windows must not be repeated");
+        }
+
+        windows
+    }
+
+    pub fn parse_sampling_interval(&self) -> u64 {
+        let interval = self
+            .sampling_interval
+            .trim()
+            .parse::<u64>()
+            .expect("sampling interval must be a positive integer");
+
+        if interval == 0 {
+            panic!("sampling interval must be a positive integer");
+        }
+
+        interval
+    }
+
+    pub fn parse_ipv6_ext_max_depth(&self) -> u8 {
+        let depth = self
+            .ipv6_ext_max_depth
+            .trim()
+            .parse::<u8>()
+            .expect("ipv6 extension headers max depth must be a positive integer");
+
+        if depth > IPV6_EXT_HEADERS_MAX {
+            panic!(
+                "ipv6 extension headers max depth can not be greater than {}",
+                IPV6_EXT_HEADERS_MAX
+            );
+        }
+
+        depth
+    }
+
+    pub fn parse_map_capacity(&self) -> u32 {
+        let capacity = self
+            .map_capacity
+            .trim()
+            .parse::<u32>()
+            .expect("map capacity must be a positive integer");
+
+        if capacity == 0 {
+            panic!("map capacity must be a positive integer");
+        }
+
+        capacity
+    }
+
+    pub fn parse_hll_capacity(&self) -> u32 {
+        let capacity = self
+            .hll_capacity
+            .trim()
+            .parse::<u32>()
+            .expect("HLL capacity must be a positive integer");
+
+        if capacity == 0 {
+            panic!("HLL capacity must be a positive integer");
+        }
+
+        capacity
+    }
+
+    pub fn parse_history_size(&self) -> usize {
+        self.history_size
+            .trim()
+            .parse::<usize>()
+            .expect("history size must be a positive integer")
+    }
+
+    pub fn parse_db_retention(&self) -> Duration {
+        let days = self
+            .db_retention
+            .trim()
+            .parse::<u64>()
+            .expect("db retention must be a positive integer");
+
+        Duration::from_secs(days * 24 * 3600)
+    }
+
+    pub fn parse_snapshot_interval(&self) -> Duration {
+        let interval = self
+            .snapshot_interval
+            .trim()
+            .parse::<u64>()
+            .expect("snapshot interval must be a positive integer");
+
+        if interval == 0 {
+            panic!("snapshot interval must be a positive integer");
+        }
+
+        Duration::from_secs(interval)
+    }
+
+    pub fn parse_snapshot_max_age(&self) -> Duration {
+        let max_age = self
+            .snapshot_max_age
+            .trim()
+            .parse::<u64>()
+            .expect("snapshot max age must be a positive integer");
+
+        Duration::from_secs(max_age)
+    }
+
+    pub fn parse_totals_max_idle(&self) -> Duration {
+        let max_idle = self
+            .totals_max_idle
+            .trim()
             .parse::<u64>()
-            .expect("windows must be a positive integer and divisable by 10");
+            .expect("totals max idle must be a positive integer");
+
+        Duration::from_secs(max_idle)
+    }
 
-        if window % 10 != 0 {
-            panic!("windows must be a positive integer and divisable by 10");
+    pub fn parse_ifaces(&self) -> Vec<String> {
+        let ifaces: Vec<String> = self
+            .iface
+            .split(',')
+            .map(|iface| iface.trim().to_string())
+            .filter(|iface| !iface.is_empty())
+            .collect();
+
+        if ifaces.is_empty() {
+            panic!("at least one interface must be provided");
         }
 
-        window
+        ifaces
+    }
+
+    pub fn parse_hook(&self) -> Hook {
+        match self.hook.trim() {
+            "xdp" => Hook::Xdp,
+            "tc-ingress" => Hook::TcIngress,
+            "tc-egress" => Hook::TcEgress,
+            hook => panic!(
+                "hook must be one of xdp, tc-ingress or tc-egress, got {}",
+                hook
+            ),
+        }
+    }
+
+    pub fn parse_xdp_mode(&self) -> XdpMode {
+        match self.xdp_mode.trim() {
+            "skb" => XdpMode::Skb,
+            "drv" => XdpMode::Drv,
+            "hw" => XdpMode::Hw,
+            "auto" => XdpMode::Auto,
+            mode => panic!("xdp mode must be one of skb, drv, hw or auto, got {}", mode),
+        }
     }
 
     pub fn parse_server_port(&self) -> u16 {
@@ -83,13 +322,7 @@ mod tests {
     use super::*;
     #[test]
     fn test_parse_custom_ports() {
-        let args = Args {
-            iface: "eth0".to_string(),
-            ports: "80,8341,22".to_string(),
-            window: "60".to_string(),
-            server_port: "3031".to_owned(),
-            serve_ip_list: false,
-        };
+        let args = Args::parse_from(["xdp-ip-counter", "--ports=80,8341,22"]);
 
         let expected = vec![80, 8341, 22];
         match args.parse_custom_ports() {
@@ -97,36 +330,165 @@ mod tests {
                 assert_eq!(ports, expected)
             }
             None => {
-                assert!(false)
+                panic!()
             }
         }
     }
 
     #[test]
-    fn test_parse_window() {
-        let args = Args {
-            iface: "eth0".to_string(),
-            ports: "80,8341,22".to_string(),
-            window: "60".to_string(),
-            server_port: "3031".to_owned(),
-            serve_ip_list: false,
-        };
+    fn test_parse_windows() {
+        let args = Args::parse_from(["xdp-ip-counter", "--ports=80,8341,22"]);
+
+        let expected = vec![60];
+        assert_eq!(args.parse_windows(), expected);
+
+        let args = Args::parse_from(["xdp-ip-counter", "--window=60,300,3600"]);
+        assert_eq!(args.parse_windows(), vec![60, 300, 3600]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_parse_windows_repeated() {
+        let args = Args::parse_from(["xdp-ip-counter", "--window=60,60"]);
+
+        args.parse_windows();
+    }
+
+    #[test]
+    fn test_parse_sampling_interval() {
+        let args = Args::parse_from(["xdp-ip-counter", "--sampling-interval=1", "--window=5"]);
 
-        let expected = 60;
-        assert_eq!(args.parse_window(), expected)
+        assert_eq!(args.parse_sampling_interval(), 1);
+        assert_eq!(args.parse_windows(), vec![5])
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_parse_window_not_multiple() {
+        let args = Args::parse_from(["xdp-ip-counter", "--sampling-interval=5", "--window=12"]);
+
+        args.parse_windows();
     }
 
     #[test]
     fn test_parse_server_port() {
-        let args = Args {
-            iface: "eth0".to_string(),
-            ports: "80,8341,22".to_string(),
-            window: "60".to_string(),
-            server_port: "3031".to_owned(),
-            serve_ip_list: false,
-        };
+        let args = Args::parse_from(["xdp-ip-counter", "--ports=80,8341,22"]);
 
         let expected = 3031;
         assert_eq!(args.parse_server_port(), expected)
     }
+
+    #[test]
+    fn test_parse_ipv6_ext_max_depth() {
+        let args = Args::parse_from(["xdp-ip-counter", "--ports=80,8341,22"]);
+
+        let expected = 4;
+        assert_eq!(args.parse_ipv6_ext_max_depth(), expected)
+    }
+
+    #[test]
+    fn test_parse_ifaces() {
+        let args = Args::parse_from(["xdp-ip-counter", "--iface=eth0, bond0,veth*"]);
+
+        assert_eq!(args.parse_ifaces(), vec!["eth0", "bond0", "veth*"]);
+    }
+
+    #[test]
+    fn test_parse_hook() {
+        let args = Args::parse_from(["xdp-ip-counter"]);
+        assert_eq!(args.parse_hook(), Hook::Xdp);
+
+        let args = Args::parse_from(["xdp-ip-counter", "--hook=tc-egress"]);
+        assert_eq!(args.parse_hook(), Hook::TcEgress);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_parse_hook_unknown() {
+        let args = Args::parse_from(["xdp-ip-counter", "--hook=tc"]);
+
+        args.parse_hook();
+    }
+
+    #[test]
+    fn test_parse_xdp_mode() {
+        let args = Args::parse_from(["xdp-ip-counter"]);
+        assert_eq!(args.parse_xdp_mode(), XdpMode::Skb);
+
+        let args = Args::parse_from(["xdp-ip-counter", "--xdp-mode=auto"]);
+        assert_eq!(args.parse_xdp_mode(), XdpMode::Auto);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_parse_xdp_mode_unknown() {
+        let args = Args::parse_from(["xdp-ip-counter", "--xdp-mode=native"]);
+
+        args.parse_xdp_mode();
+    }
+
+    #[test]
+    fn test_parse_map_capacity() {
+        let args = Args::parse_from(["xdp-ip-counter", "--map-capacity=65536", "--lru"]);
+
+        assert_eq!(args.parse_map_capacity(), 65536);
+        assert!(args.lru);
+    }
+
+    #[test]
+    fn test_parse_hll_capacity() {
+        let args = Args::parse_from(["xdp-ip-counter", "--hll"]);
+        assert_eq!(args.parse_hll_capacity(), 1024);
+
+        let args = Args::parse_from(["xdp-ip-counter", "--hll", "--hll-capacity=4096"]);
+        assert_eq!(args.parse_hll_capacity(), 4096);
+    }
+
+    #[test]
+    fn test_parse_db_retention() {
+        let args = Args::parse_from(["xdp-ip-counter", "--db=/var/lib/xdp-ip-counter.db"]);
+
+        assert_eq!(args.db.as_deref(), Some("/var/lib/xdp-ip-counter.db"));
+        assert_eq!(
+            args.parse_db_retention(),
+            Duration::from_secs(30 * 24 * 3600)
+        )
+    }
+
+    #[test]
+    fn test_parse_snapshot() {
+        let args = Args::parse_from([
+            "xdp-ip-counter",
+            "--snapshot=/var/lib/xdp-ip-counter.snapshot",
+            "--snapshot-interval=30",
+        ]);
+
+        assert_eq!(
+            args.snapshot.as_deref(),
+            Some("/var/lib/xdp-ip-counter.snapshot")
+        );
+        assert_eq!(args.parse_snapshot_interval(), Duration::from_secs(30));
+        assert_eq!(args.parse_snapshot_max_age(), Duration::from_secs(600));
+    }
+
+    #[test]
+    fn test_parse_totals_max_idle() {
+        let args = Args::parse_from(["xdp-ip-counter"]);
+        assert_eq!(args.parse_totals_max_idle(), Duration::from_secs(3600));
+
+        let args = Args::parse_from(["xdp-ip-counter", "--totals-max-idle=600"]);
+        assert_eq!(args.parse_totals_max_idle(), Duration::from_secs(600));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_parse_ipv6_ext_max_depth_too_deep() {
+        let args = Args::parse_from([
+            "xdp-ip-counter",
+            "--ports=80,8341,22",
+            "--ipv6-ext-max-depth=9",
+        ]);
+
+        args.parse_ipv6_ext_max_depth();
+    }
 }
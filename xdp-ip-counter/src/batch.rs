@@ -0,0 +1,141 @@
+use aya::{util::nr_cpus, Pod};
+use std::{cell::Cell, collections::HashMap, fs, io, mem, os::unix::io::RawFd, ptr};
+
+use crate::pin::bpf;
+
+// See include/uapi/linux/bpf.h
+const BPF_OBJ_GET_INFO_BY_FD: libc::c_int = 15;
+const BPF_MAP_LOOKUP_AND_DELETE_BATCH: libc::c_int = 25;
+const BPF_OBJ_NAME_LEN: usize = 16;
+
+/// Entries asked for per batch at first. Doubled when a hash bucket holds more (ENOSPC).
+const BATCH_SIZE: u32 = 1024;
+
+/// The BPF_MAP_*_BATCH part of union bpf_attr.
+#[repr(C)]
+struct BatchAttr {
+    in_batch: u64,
+    out_batch: u64,
+    keys: u64,
+    values: u64,
+    // Set by the kernel to the number of entries it returned
+    count: Cell<u32>,
+    map_fd: u32,
+    elem_flags: u64,
+    flags: u64,
+}
+
+/// The BPF_OBJ_GET_INFO_BY_FD part of union bpf_attr.
+#[repr(C)]
+struct InfoAttr {
+    bpf_fd: u32,
+    info_len: u32,
+    info: u64,
+}
+
+/// The start of struct bpf_map_info, up to the map's name.
+#[repr(C)]
+#[derive(Default)]
+struct MapInfo {
+    map_type: u32,
+    id: u32,
+    key_size: u32,
+    value_size: u32,
+    max_entries: u32,
+    map_flags: u32,
+    name: [u8; BPF_OBJ_NAME_LEN],
+}
+
+/// Returns the file descriptors of the maps this process has open, by name.
+/// aya does not expose them, but names the maps it creates after their definition (on Linux 4.15 and later).
+/// The descriptors remain owned by aya and stay valid as long as the maps are loaded.
+pub fn map_fds() -> io::Result<HashMap<String, RawFd>> {
+    let mut fds = HashMap::new();
+    for entry in fs::read_dir("/proc/self/fd")? {
+        let entry = entry?;
+        let is_map = fs::read_link(entry.path())
+            .map(|target| target.to_string_lossy() == "anon_inode:bpf-map")
+            .unwrap_or(false);
+        let fd = match entry.file_name().to_string_lossy().parse::<RawFd>() {
+            Ok(fd) if is_map => fd,
+            _ => continue,
+        };
+        let mut info = MapInfo::default();
+        bpf(
+            BPF_OBJ_GET_INFO_BY_FD,
+            &InfoAttr {
+                bpf_fd: fd as u32,
+                info_len: mem::size_of::<MapInfo>() as u32,
+                info: &mut info as *mut MapInfo as u64,
+            },
+        )?;
+        let len = info
+            .name
+            .iter()
+            .position(|&c| c == 0)
+            .unwrap_or(BPF_OBJ_NAME_LEN);
+        if len > 0 {
+            fds.insert(String::from_utf8_lossy(&info.name[..len]).into_owned(), fd);
+        }
+    }
+    Ok(fds)
+}
+
+/// Reads and deletes all the entries of the per-cpu hash map `fd` into `entries`, with their value on each CPU.
+/// Takes a syscall per batch of entries.
+/// Fails with EINVAL if the kernel lacks batch operations (before Linux 5.6), in which case nothing has been read.
+pub fn drain<K: Pod, V: Pod>(fd: RawFd, entries: &mut Vec<(K, Vec<V>)>) -> io::Result<()> {
+    let nr_cpus = nr_cpus()?;
+    // Per-cpu values are laid out one after the other, each aligned to 8 bytes
+    let value_size = (mem::size_of::<V>() + 7) & !7;
+    let mut batch_size = BATCH_SIZE;
+    // Opaque position of the kernel in the map, hash maps use the index of a bucket
+    let mut in_batch = [0u8; 8];
+    let mut out_batch = [0u8; 8];
+    let mut first = true;
+    loop {
+        let mut keys = vec![0u8; batch_size as usize * mem::size_of::<K>()];
+        let mut values = vec![0u8; batch_size as usize * nr_cpus * value_size];
+        let attr = BatchAttr {
+            in_batch: match first {
+                true => 0,
+                false => in_batch.as_ptr() as u64,
+            },
+            out_batch: out_batch.as_mut_ptr() as u64,
+            keys: keys.as_mut_ptr() as u64,
+            values: values.as_mut_ptr() as u64,
+            count: Cell::new(batch_size),
+            map_fd: fd as u32,
+            elem_flags: 0,
+            flags: 0,
+        };
+        let done = match bpf(BPF_MAP_LOOKUP_AND_DELETE_BATCH, &attr) {
+            Ok(_) => false,
+            // Returned along with the last entries
+            Err(err) if err.raw_os_error() == Some(libc::ENOENT) => true,
+            // A bucket holds more entries than asked for
+            Err(err) if err.raw_os_error() == Some(libc::ENOSPC) => {
+                batch_size *= 2;
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
+        for i in 0..attr.count.get() as usize {
+            let key = unsafe {
+                ptr::read_unaligned(keys.as_ptr().add(i * mem::size_of::<K>()) as *const K)
+            };
+            let per_cpu = (0..nr_cpus)
+                .map(|cpu| {
+                    let offset = (i * nr_cpus + cpu) * value_size;
+                    unsafe { ptr::read_unaligned(values.as_ptr().add(offset) as *const V) }
+                })
+                .collect();
+            entries.push((key, per_cpu));
+        }
+        if done {
+            return Ok(());
+        }
+        in_batch = out_batch;
+        first = false;
+    }
+}
@@ -1,133 +1,746 @@
-use anyhow::Context;
+use anyhow::{bail, Context};
 use aya::{
-    include_bytes_aligned,
-    programs::{Xdp, XdpFlags},
-    Bpf,
+    include_bytes_aligned, maps,
+    programs::{
+        tc::{self, SchedClassifierLinkId},
+        xdp::XdpLinkId,
+        SchedClassifier, TcAttachType, Xdp,
+    },
+    Bpf, BpfLoader,
 };
 use aya_log::BpfLogger;
-use log::warn;
+use log::{info, warn};
+use object::{Object, ObjectSection, ObjectSymbol};
 use std::{
-    collections::HashSet,
-    sync::{Arc, RwLock},
+    collections::HashMap,
+    fmt::Display,
+    io,
+    net::IpAddr,
+    path::Path,
+    sync::{Arc, Mutex, RwLock},
+    time::SystemTime,
 };
 use tokio::time::{sleep, Duration};
+use xdp_ip_counter_common::{FlowStats, HllKey, HllRegisters};
 
 use crate::{
     args::Args,
-    structs::{L3Proto, L4Proto, LocalMap, SharedMaps},
+    netlink::{self, IfaceNames, LinkEvent, LinkEvents},
+    pin::{self, PIN_PATH},
+    rcu::Barrier,
+    storage::Storage,
+    structs::{
+        Encap, Flow, Hook, IpMap, L3Proto, L4Proto, LocalMap, SharedMaps, Traffic, XdpMode,
+        COUNTERS,
+    },
 };
 
-pub fn init(args: &Args) -> Bpf {
+/// Loads the ebpf program and its maps, without attaching it anywhere yet so
+/// that the maps can be configured first (see attach).
+pub fn load(args: &Args) -> Bpf {
     #[cfg(debug_assertions)]
-    let mut bpf = Bpf::load(include_bytes_aligned!(
-        "../../target/bpfel-unknown-none/debug/xdp-ip-counter"
-    ))
-    .expect("error while loding ebpf bytecode");
+    let bytecode = include_bytes_aligned!("../../target/bpfel-unknown-none/debug/xdp-ip-counter");
 
     #[cfg(not(debug_assertions))]
-    let mut bpf = Bpf::load(include_bytes_aligned!(
-        "../../target/bpfel-unknown-none/release/xdp-ip-counter"
-    ))
-    .expect("error while loding ebpf bytecode");
+    let bytecode = include_bytes_aligned!("../../target/bpfel-unknown-none/release/xdp-ip-counter");
+
+    let hook = args.parse_hook();
+    if args.pin && hook != Hook::Xdp {
+        panic!("--pin is only supported with --hook=xdp");
+    }
+
+    let bytecode = configure_maps(
+        bytecode,
+        args.parse_map_capacity(),
+        args.lru,
+        args.hll.then(|| args.parse_hll_capacity()),
+        args.pin,
+    )
+    .expect("error while configuring ebpf maps");
+    let mut loader = BpfLoader::new();
+    if args.pin {
+        check_pinned_maps(bytecode.as_bytes()).expect("pinned maps cannot be reused");
+        std::fs::create_dir_all(PIN_PATH).expect("failed to create the pin directory");
+        // Maps already pinned by a previous run are reused instead of being created
+        loader.map_pin_path(PIN_PATH);
+    }
+    let mut bpf = loader
+        .load(bytecode.as_bytes())
+        .expect("error while loding ebpf bytecode");
 
     if let Err(e) = BpfLogger::init(&mut bpf) {
         // This can happen if you remove all log statements from your eBPF program.
         warn!("failed to initialize eBPF logger: {}", e);
     }
-    let program: &mut Xdp = bpf
-        .program_mut("xdp_ip_counter")
+    // With --pin, the program is only loaded if no pinned link is reused (see pin::attach)
+    let loaded = match hook {
+        Hook::Xdp if args.pin => Ok(()),
+        Hook::Xdp => xdp_program(&mut bpf).load(),
+        Hook::TcIngress | Hook::TcEgress => tc_program(&mut bpf, hook).load(),
+    };
+    loaded.expect("error while loading the ebpf program");
+
+    bpf
+}
+
+/// Attaches the loaded program to the interfaces matching --iface.
+/// Returns it along with the interfaces it is attached to, see Attachments.
+pub fn attach(args: &Args, bpf: Bpf) -> Attachments {
+    let mut attachments = Attachments {
+        bpf,
+        attached: HashMap::new(),
+        patterns: args.parse_ifaces(),
+        hook: args.parse_hook(),
+        mode: args.parse_xdp_mode(),
+        pinned: args.pin,
+    };
+    for (ifindex, iface) in matching_ifaces(&attachments.patterns) {
+        let attachment = attachments
+            .attach(&iface)
+            .with_context(|| format!("failed to attach the program to {}", iface))
+            .unwrap();
+        info!("program attached to {}", attachment);
+        attachments.attached.insert(ifindex, attachment);
+    }
+
+    attachments
+}
+
+fn xdp_program(bpf: &mut Bpf) -> &mut Xdp {
+    bpf.program_mut(Hook::Xdp.program())
+        .unwrap()
+        .try_into()
+        .expect("error while getting the program")
+}
+
+fn tc_program(bpf: &mut Bpf, hook: Hook) -> &mut SchedClassifier {
+    bpf.program_mut(hook.program())
         .unwrap()
         .try_into()
-        .expect("error while getting the program");
+        .expect("error while getting the program")
+}
 
-    program
-        .load()
-        .expect("error while loading the ebpf program");
-    program
-        .attach(&args.iface, XdpFlags::SKB_MODE)
-        .context("failed to attach the XDP program with the provided flag")
-        .unwrap();
+/// Returns the index and name of the interfaces of the host matching `patterns`, in the order they were listed.
+/// Panics if an interface listed by name (not a pattern) does not exist, or if nothing matches at all.
+fn matching_ifaces(patterns: &[String]) -> Vec<(u32, String)> {
+    let interfaces = netlink::interfaces().expect("failed to list network interfaces");
+    let mut ifaces: Vec<(u32, String)> = Vec::new();
+    for pattern in patterns {
+        let mut matched = false;
+        for (ifindex, name) in interfaces.iter() {
+            if netlink::iface_matches(pattern, name) {
+                matched = true;
+                if !ifaces.iter().any(|(i, _)| i == ifindex) {
+                    ifaces.push((*ifindex, name.clone()));
+                }
+            }
+        }
+        if !matched {
+            match pattern.contains(['*', '?']) {
+                true => warn!("no interface matches {}", pattern),
+                false => panic!("unknown interface {}", pattern),
+            }
+        }
+    }
 
-    bpf
+    if ifaces.is_empty() {
+        panic!("no interface matches {}", patterns.join(","));
+    }
+    ifaces
+}
+
+/// Attachment is the program being attached to an interface.
+pub struct Attachment {
+    pub iface: String,
+    pub hook: Hook,
+    /// None for TC hooks.
+    pub mode: Option<XdpMode>,
+    link: Link,
+}
+impl Display for Attachment {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.mode {
+            Some(mode) => write!(f, "{} ({} in {} mode)", self.iface, self.hook, mode),
+            None => write!(f, "{} ({})", self.iface, self.hook),
+        }
+    }
+}
+
+/// Link is what detaches the program from an interface.
+enum Link {
+    Xdp(XdpLinkId),
+    Tc(SchedClassifierLinkId),
+    // With --pin, the link is detached by removing its pin.
+    Pinned,
+}
+
+/// Attachments holds the loaded program and keeps track of the interfaces it is attached to,
+/// so that it can follow interfaces matching --iface as they appear and disappear.
+pub struct Attachments {
+    bpf: Bpf,
+    // Keyed by interface index, as names can be reused.
+    attached: HashMap<u32, Attachment>,
+    patterns: Vec<String>,
+    hook: Hook,
+    // Only used by the XDP hook.
+    mode: XdpMode,
+    pinned: bool,
+}
+impl Attachments {
+    pub fn attached(&self) -> impl Iterator<Item = &Attachment> {
+        self.attached.values()
+    }
+
+    /// Attaches the program to interfaces matching --iface as they are created, and detaches it from those deleted.
+    /// Returns how the event was handled, if it concerned an interface of interest.
+    fn handle(&mut self, event: LinkEvent) -> Option<(&'static str, String)> {
+        match event {
+            LinkEvent::New { ifindex, name } => {
+                // Attaching the program itself changes the link, which is notified as RTM_NEWLINK too
+                if self.attached.contains_key(&ifindex)
+                    || !self
+                        .patterns
+                        .iter()
+                        .any(|pattern| netlink::iface_matches(pattern, &name))
+                {
+                    return None;
+                }
+                match self.attach(&name) {
+                    Ok(attachment) => {
+                        info!(
+                            "interface {} appeared, program attached to {}",
+                            name, attachment
+                        );
+                        self.attached.insert(ifindex, attachment);
+                        Some(("attached", name))
+                    }
+                    Err(err) => {
+                        warn!(
+                            "interface {} appeared, failed to attach the program: {:#}",
+                            name, err
+                        );
+                        Some(("attach_failed", name))
+                    }
+                }
+            }
+            LinkEvent::Del { ifindex, name } => {
+                let attachment = self.attached.remove(&ifindex)?;
+                self.detach(attachment);
+                if self.hook != Hook::Xdp {
+                    if let Err(err) = self.set_l3_iface(ifindex, false) {
+                        warn!("failed to forget the type of {}: {:#}", name, err);
+                    }
+                }
+                info!("interface {} disappeared, program detached", name);
+                Some(("detached", name))
+            }
+        }
+    }
+
+    /// Attaches the program to `iface` at the configured hook.
+    fn attach(&mut self, iface: &str) -> Result<Attachment, anyhow::Error> {
+        match self.hook {
+            Hook::Xdp => attach_xdp(xdp_program(&mut self.bpf), iface, self.mode, self.pinned),
+            Hook::TcIngress | Hook::TcEgress => {
+                let ifindex = netlink::ifindex(iface)
+                    .with_context(|| format!("unknown interface {}", iface))?;
+                let ethernet = netlink::has_ethernet_header(ifindex)
+                    .context("failed to get the type of the interface")?;
+                if !ethernet {
+                    info!(
+                        "{} has no Ethernet header, its packets are parsed from their IP header",
+                        iface
+                    );
+                }
+                // Before attaching, for the first packets to be parsed right
+                self.set_l3_iface(ifindex, !ethernet)?;
+                attach_tc(tc_program(&mut self.bpf, self.hook), iface, self.hook)
+            }
+        }
+    }
+
+    /// Tells the classifiers whether packets of interface `ifindex` start with their IP header (see L3_IFACES).
+    fn set_l3_iface(&self, ifindex: u32, l3: bool) -> Result<(), anyhow::Error> {
+        let mut l3_ifaces: maps::HashMap<_, u32, u8> =
+            maps::HashMap::try_from(self.bpf.map_mut("L3_IFACES")?)?;
+        if l3 {
+            l3_ifaces.insert(ifindex, 1, 0)?;
+        } else if l3_ifaces.get(&ifindex, 0).is_ok() {
+            // The index may have belonged to another interface
+            l3_ifaces.remove(&ifindex)?;
+        }
+        Ok(())
+    }
+
+    fn detach(&mut self, attachment: Attachment) {
+        // The interface may be gone already, with the program detached by the kernel
+        let detached = match attachment.link {
+            Link::Xdp(link) => xdp_program(&mut self.bpf)
+                .detach(link)
+                .map_err(anyhow::Error::from),
+            Link::Tc(link) => tc_program(&mut self.bpf, attachment.hook)
+                .detach(link)
+                .map_err(anyhow::Error::from),
+            Link::Pinned => pin::detach(&attachment.iface).map_err(anyhow::Error::from),
+        };
+        if let Err(err) = detached {
+            warn!(
+                "failed to detach the program from {}: {:#}",
+                attachment.iface, err
+            );
+        }
+    }
+
+    /// Detaches the program from every interface, except with --pin where it stays attached once the process exits.
+    pub fn detach_all(&mut self) {
+        if self.pinned {
+            return;
+        }
+        for (_, attachment) in std::mem::take(&mut self.attached) {
+            self.detach(attachment);
+        }
+    }
+}
+
+/// Follows interfaces as they appear and disappear, see Attachments::handle().
+/// Blocks forever, so it is meant to run on its own thread.
+pub fn follow_ifaces(
+    attachments: Arc<Mutex<Attachments>>,
+    events: LinkEvents,
+    local_map: Arc<RwLock<LocalMap>>,
+) {
+    loop {
+        let events = match events.recv() {
+            Ok(events) => events,
+            Err(err) => {
+                // Notifications have been dropped (ENOBUFS), the next ones are still received
+                warn!("failed to receive link events: {}", err);
+                continue;
+            }
+        };
+        for event in events {
+            let mut attachments = attachments
+                .lock()
+                .expect("unable to accuire lock for attachments");
+            if let Some((handled, iface)) = attachments.handle(event) {
+                let mut local_map = local_map
+                    .write()
+                    .expect("unable to accuire lock for local_map");
+                local_map.count_iface_event(handled);
+                if handled == "detached" {
+                    // Interfaces of containers come and go, their series would otherwise pile up
+                    local_map.remove_iface_totals(&iface);
+                }
+                let attached = attachments.attached().find(|a| a.iface == iface);
+                match attached.and_then(|attachment| attachment.mode) {
+                    Some(mode) => local_map.set_xdp_mode(&iface, mode),
+                    None => local_map.remove_xdp_mode(&iface),
+                }
+            }
+        }
+    }
+}
+
+/// Attaches the program to `iface` in `mode`, trying driver mode then generic mode for XdpMode::Auto.
+/// The mode of the returned attachment is the one the kernel reports, which may differ when --pin reuses a link.
+fn attach_xdp(
+    program: &mut Xdp,
+    iface: &str,
+    mode: XdpMode,
+    pinned: bool,
+) -> Result<Attachment, anyhow::Error> {
+    let modes = match mode {
+        XdpMode::Auto => vec![XdpMode::Drv, XdpMode::Skb],
+        mode => vec![mode],
+    };
+    for (i, mode) in modes.iter().enumerate() {
+        let attached = match pinned {
+            true => pin::attach(program, iface, mode.flags()).map(|_| Link::Pinned),
+            false => program
+                .attach(iface, mode.flags())
+                .map(Link::Xdp)
+                .map_err(anyhow::Error::from),
+        };
+        match attached {
+            Ok(link) => {
+                let reported = netlink::ifindex(iface).map(netlink::xdp_mode);
+                let mode = match reported {
+                    Some(Ok(Some(reported))) => reported,
+                    _ => {
+                        warn!(
+                            "unable to tell in which mode the XDP program is attached to {}",
+                            iface
+                        );
+                        *mode
+                    }
+                };
+                return Ok(Attachment {
+                    iface: iface.to_string(),
+                    hook: Hook::Xdp,
+                    mode: Some(mode),
+                    link,
+                });
+            }
+            Err(err) if i + 1 < modes.len() => {
+                warn!(
+                    "failed to attach the XDP program to {} in {} mode, falling back: {:#}",
+                    iface, mode, err
+                )
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    unreachable!("there is always a mode to attach in")
+}
+
+/// Attaches the classifier of `hook` to `iface`, adding the clsact qdisc first if the interface has none.
+fn attach_tc(
+    program: &mut SchedClassifier,
+    iface: &str,
+    hook: Hook,
+) -> Result<Attachment, anyhow::Error> {
+    match tc::qdisc_add_clsact(iface) {
+        Err(err) if err.kind() != io::ErrorKind::AlreadyExists => {
+            return Err(err).context("failed to add the clsact qdisc")
+        }
+        _ => {}
+    }
+    let attach_type = match hook {
+        Hook::TcEgress => TcAttachType::Egress,
+        _ => TcAttachType::Ingress,
+    };
+    let link = program.attach(iface, attach_type)?;
+
+    Ok(Attachment {
+        iface: iface.to_string(),
+        hook,
+        mode: None,
+        link: Link::Tc(link),
+    })
+}
+
+// Flow maps, each coming in two generations (see SharedMaps).
+const FLOW_MAPS: [&str; 8] = [
+    "IP_V4_0",
+    "IP_V4_1",
+    "IP_V6_0",
+    "IP_V6_1",
+    "ICMP_V4_0",
+    "ICMP_V4_1",
+    "ICMP_V6_0",
+    "ICMP_V6_1",
+];
+// HyperLogLog maps, preallocated with 1 KiB of registers per entry and per CPU.
+const HLL_MAPS: [&str; 2] = ["HLL_0", "HLL_1"];
+const BPF_MAP_TYPE_LRU_PERCPU_HASH: u32 = 10;
+const PINNING_BY_NAME: u32 = 1;
+
+/// A copy of the ebpf bytecode, kept 8-byte aligned like include_bytes_aligned! does since parsing it requires so.
+struct Bytecode {
+    words: Vec<u64>,
+    len: usize,
+}
+impl Bytecode {
+    fn new(data: &[u8]) -> Self {
+        let mut bytecode = Self {
+            words: vec![0; data.len().div_ceil(8)],
+            len: data.len(),
+        };
+        bytecode.as_bytes_mut().copy_from_slice(data);
+        bytecode
+    }
+    fn as_bytes(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.words.as_ptr() as *const u8, self.len) }
+    }
+    fn as_bytes_mut(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.words.as_mut_ptr() as *mut u8, self.len) }
+    }
+}
+
+/// Sets the capacity of the flow maps and, if `lru` is set, turns them into LRU maps.
+/// The HyperLogLog maps are sized to `hll_capacity`, or shrunk to a single entry if it is None as they are not used.
+/// If `pin` is set, all maps are pinned by name (see BpfLoader::map_pin_path).
+/// Map definitions are patched in the bytecode itself as they are created from it while loading.
+fn configure_maps(
+    data: &[u8],
+    capacity: u32,
+    lru: bool,
+    hll_capacity: Option<u32>,
+    pin: bool,
+) -> Result<Bytecode, anyhow::Error> {
+    let mut bytecode = Bytecode::new(data);
+    let bytes = bytecode.as_bytes_mut();
+    for (name, offset) in map_defs(data)? {
+        if pin {
+            bytes[offset + 24..offset + 28].copy_from_slice(&PINNING_BY_NAME.to_le_bytes());
+        }
+        if HLL_MAPS.contains(&name.as_str()) {
+            let hll_capacity = hll_capacity.unwrap_or(1);
+            bytes[offset + 12..offset + 16].copy_from_slice(&hll_capacity.to_le_bytes());
+        }
+        if !FLOW_MAPS.contains(&name.as_str()) {
+            continue;
+        }
+        if lru {
+            bytes[offset..offset + 4].copy_from_slice(&BPF_MAP_TYPE_LRU_PERCPU_HASH.to_le_bytes());
+        }
+        bytes[offset + 12..offset + 16].copy_from_slice(&capacity.to_le_bytes());
+    }
+
+    Ok(bytecode)
+}
+
+/// Returns the name of the maps defined in the bytecode along with the offset of their definition,
+/// a struct bpf_map_def { type, key_size, value_size, max_entries, map_flags, id, pinning } (all u32).
+fn map_defs(data: &[u8]) -> Result<Vec<(String, usize)>, anyhow::Error> {
+    let file = object::File::parse(data)?;
+    let section = file
+        .section_by_name("maps")
+        .context("maps section not found")?;
+    let (section_offset, _) = section.file_range().context("maps section has no data")?;
+
+    let mut defs = Vec::new();
+    for symbol in file.symbols() {
+        if symbol.section_index() != Some(section.index()) {
+            continue;
+        }
+        let offset = (section_offset + symbol.address()) as usize;
+        defs.push((symbol.name()?.to_string(), offset));
+    }
+    Ok(defs)
+}
+
+/// Makes sure the maps a previous run pinned, which BpfLoader reuses as they are, have the type and capacity the
+/// bytecode defines, e.g. that --map-capacity, --lru and --hll have not changed since.
+fn check_pinned_maps(data: &[u8]) -> Result<(), anyhow::Error> {
+    for (name, offset) in map_defs(data)? {
+        let path = Path::new(PIN_PATH).join(&name);
+        let pinned = match pin::pinned_map_def(&path)? {
+            Some(pinned) => pinned,
+            None => continue,
+        };
+        let u32_at = |at: usize| u32::from_le_bytes(data[at..at + 4].try_into().unwrap());
+        let expected = (u32_at(offset), u32_at(offset + 12));
+        if pinned != expected {
+            bail!(
+                "the map pinned to {} has type {} and {} entries instead of type {} and {} entries, remove {} to apply the new settings",
+                path.display(),
+                pinned.0,
+                pinned.1,
+                expected.0,
+                expected.1,
+                PIN_PATH
+            );
+        }
+    }
+    Ok(())
 }
 
 pub async fn collect(
     shared_maps: &mut SharedMaps,
     local_map: Arc<RwLock<LocalMap>>,
-    aggregate_window: u64,
+    sampling_interval: u64,
+    totals_max_idle: Duration,
+    map_capacity: u32,
+    lru: bool,
+    storage: Option<Arc<Mutex<Storage>>>,
 ) {
-    // Program reads from ebpf maps (shared maps) to local_map's tmp area each SAMPLING_SECONDS seconds then clears ebpf maps.
-    const SAMPLING_SECONDS: u64 = 10;
-    let sampling_duration = Duration::from_secs(SAMPLING_SECONDS);
+    // Program reads from ebpf maps (shared maps) to local_map's tmp area each sampling_interval seconds then clears ebpf maps.
+    // Flow maps come in two generations: the ebpf program records into one while the other one is read and cleared.
+    let sampling_duration = Duration::from_secs(sampling_interval);
+    // Program runs that read the previous generation before the flip may still be writing to it. They run within an RCU
+    // read-side critical section, which the barrier waits for. Should it be unavailable (maps of maps are not allowed),
+    // sleeping is a heuristic bound: runs complete within microseconds, so this leaves plenty of room.
+    const FLIP_GRACE_PERIOD: Duration = Duration::from_millis(10);
+    let barrier = match Barrier::new() {
+        Ok(barrier) => Some(barrier),
+        Err(err) => {
+            warn!(
+                "failed to create the RCU barrier, waiting {:?} after each flip instead: {}",
+                FLIP_GRACE_PERIOD, err
+            );
+            None
+        }
+    };
 
-    // Each aggregate_window seconds (when aggr_counter >= aggregate_window), data read to local_map's tmp area is added to local_map's aggr area.
-    // The idea is to clear ebps maps every sampling_duration seconds no matter what aggregate_window user wants since ebpf maps' capacities are limited.
+    // Each aggregate window (see --window), data read to local_map's tmp areas is moved to its aggr area. Windows are tracked by local_map.
+    // The idea is to clear ebps maps every sampling_duration seconds no matter what windows user wants since ebpf maps' capacities are limited.
     // See the definition of LocalMap for more details.
-    let mut aggr_counter = 0;
-
-    // Records ip addresses as in their original type to later be used to empty ebpf maps.
-    let mut ipv4_orig: HashSet<[u8; 4]> = HashSet::new();
-    let mut ipv6_orig: HashSet<[u16; 8]> = HashSet::new();
 
     loop {
         sleep(sampling_duration).await;
 
-        for i in shared_maps.get_tcp_v4().iter() {
-            let (ip, port) = i.unwrap();
-            if let Ok(ref mut map) = local_map.write() {
-                map.add_tmp(L3Proto::Ipv4, L4Proto::Tcp(port), ip)
-            } else {
-                println!("failed")
+        let generation = match shared_maps.flip_generation() {
+            Ok(generation) => generation,
+            Err(err) => {
+                warn!("failed to flip the generation of flow maps: {}", err);
+                continue;
             }
-            ipv4_orig.insert(ip);
-        }
-        for i in shared_maps.get_udp_v4().iter() {
-            let (ip, port) = i.unwrap();
-            if let Ok(ref mut map) = local_map.write() {
-                map.add_tmp(L3Proto::Ipv4, L4Proto::Udp(port), ip)
-            } else {
-                println!("failed")
+        };
+        // Letting packets that were being processed while flipping land in the retired generation
+        let waited = barrier
+            .as_ref()
+            .map(|barrier| tokio::task::block_in_place(|| barrier.wait()));
+        match waited {
+            Some(Ok(())) => {}
+            Some(Err(err)) => {
+                warn!("failed to wait for an RCU grace period: {}", err);
+                sleep(FLIP_GRACE_PERIOD).await;
             }
-            ipv4_orig.insert(ip);
+            None => sleep(FLIP_GRACE_PERIOD).await,
         }
 
-        for i in shared_maps.get_tcp_v6().iter() {
-            let (ip, port) = i.unwrap();
-            if let Ok(ref mut map) = local_map.write() {
-                map.add_tmp(L3Proto::Ipv6, L4Proto::Tcp(port), ip)
-            } else {
-                println!("failed")
+        // Computed on each sample so that wall-clock adjustments are taken into account
+        let boot_time = boot_time();
+        // Looked up again on each sample as interfaces come and go
+        let mut ifaces = IfaceNames::default();
+        // Emptying the retired generation, the ebpf program no longer writes to it so nothing is lost.
+        let read = [
+            (
+                "IP_V4",
+                read_flows(
+                    shared_maps.drain_ip_v4(generation),
+                    &local_map,
+                    boot_time,
+                    &mut ifaces,
+                ),
+            ),
+            (
+                "IP_V6",
+                read_flows(
+                    shared_maps.drain_ip_v6(generation),
+                    &local_map,
+                    boot_time,
+                    &mut ifaces,
+                ),
+            ),
+            (
+                "ICMP_V4",
+                read_flows(
+                    shared_maps.drain_icmp_v4(generation),
+                    &local_map,
+                    boot_time,
+                    &mut ifaces,
+                ),
+            ),
+            (
+                "ICMP_V6",
+                read_flows(
+                    shared_maps.drain_icmp_v6(generation),
+                    &local_map,
+                    boot_time,
+                    &mut ifaces,
+                ),
+            ),
+        ];
+        read_sketches(shared_maps.drain_hll(generation), &local_map, &mut ifaces);
+
+        if let Ok(ref mut map) = local_map.write() {
+            for counter in COUNTERS {
+                map.set_counter(counter.index, shared_maps.get_counter(counter.index));
             }
-            ipv6_orig.insert(ip);
-        }
-        for i in shared_maps.get_udp_v6().iter() {
-            let (ip, port) = i.unwrap();
-            if let Ok(ref mut map) = local_map.write() {
-                map.add_tmp(L3Proto::Ipv6, L4Proto::Udp(port), ip)
-            } else {
-                println!("failed")
+            // The ebpf program counts the flows a full map drops, but not those an LRU map evicts
+            for (name, flows) in read {
+                if flows >= map_capacity as usize {
+                    warn!(
+                        "{} was full during the last sample, flows were {}",
+                        name,
+                        if lru { "evicted" } else { "dropped" }
+                    );
+                    map.count_full_map(name);
+                }
             }
-            ipv6_orig.insert(ip);
+            map.aggr(sampling_interval);
+            map.expire_totals(totals_max_idle);
         }
 
-        // Removing items from original ebpf maps, each {duration} seconds
-        for ip in ipv4_orig.iter() {
-            shared_maps.remove_from_tcp_v4(ip);
-            shared_maps.remove_from_udp_v4(ip);
+        if let (Some(storage), Ok(map)) = (storage.as_ref(), local_map.read()) {
+            // Copying the complete windows so that writing them, which may take a while, holds neither lock
+            let complete: Vec<(String, IpMap)> = map
+                .get_windows()
+                .iter()
+                .filter(|w| w.is_complete())
+                .map(|w| (w.label().to_string(), w.get_ips().clone()))
+                .collect();
+            drop(map);
+            if !complete.is_empty() {
+                let storage = storage.clone();
+                let now = SystemTime::now();
+                tokio::task::spawn_blocking(move || {
+                    let mut storage = storage.lock().expect("unable to accuire lock for storage");
+                    for (label, ips) in complete {
+                        if let Err(err) = storage.store(now, &label, &ips) {
+                            warn!("failed to persist the {} window: {}", label, err);
+                        }
+                    }
+                });
+            }
         }
-        ipv4_orig.clear();
-        for ip in ipv6_orig.iter() {
-            shared_maps.remove_from_tcp_v6(ip);
-            shared_maps.remove_from_udp_v6(ip);
+    }
+}
+
+/// Reads flows drained from an ebpf map into local_map's tmp area.
+/// Returns the number of flows the map held.
+fn read_flows<K: Flow>(
+    flows: Vec<(K, Vec<FlowStats>)>,
+    local_map: &Arc<RwLock<LocalMap>>,
+    boot_time: SystemTime,
+    ifaces: &mut IfaceNames,
+) -> usize
+where
+    IpAddr: From<K::Addr>,
+{
+    let len = flows.len();
+    for (flow, stats) in flows {
+        let meta = flow.meta();
+        if let Some(l4_proto) = L4Proto::new(meta.proto, meta.port) {
+            // Each CPU accounts the packets it has processed separately
+            let mut traffic = Traffic::default();
+            for stats in stats.iter().filter(|stats| stats.packets > 0) {
+                traffic.add(&Traffic::new(stats, boot_time));
+            }
+            match local_map.write() {
+                Ok(mut map) => map.add_tmp(
+                    K::l3_proto(),
+                    l4_proto,
+                    flow.addr(),
+                    Encap::from(meta).with_iface(ifaces.get(meta.ifindex)),
+                    traffic,
+                ),
+                Err(err) => warn!("failed to record a flow: {}", err),
+            }
         }
-        ipv6_orig.clear();
+    }
+    len
+}
 
-        aggr_counter += SAMPLING_SECONDS;
-        if aggr_counter >= aggregate_window {
-            aggr_counter = 0;
+/// Returns the wall-clock time at which CLOCK_MONOTONIC, the clock behind bpf_ktime_get_ns(), started.
+fn boot_time() -> SystemTime {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    SystemTime::now() - Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32)
+}
 
-            if let Ok(ref mut local_map) = local_map.write() {
-                local_map.aggr();
+/// Merges the per-cpu HyperLogLog sketches drained from an ebpf map into local_map's tmp area.
+fn read_sketches(
+    sketches: Vec<(HllKey, Vec<HllRegisters>)>,
+    local_map: &Arc<RwLock<LocalMap>>,
+    ifaces: &mut IfaceNames,
+) {
+    for (key, registers) in sketches {
+        let l3_proto = match key.network {
+            4 => L3Proto::Ipv4,
+            _ => L3Proto::Ipv6,
+        };
+        if let Some(l4_proto) = L4Proto::new(key.proto, key.port) {
+            let encap = Encap::default().with_iface(ifaces.get(key.ifindex));
+            if let Ok(ref mut map) = local_map.write() {
+                for registers in registers.iter() {
+                    map.merge_sketch_tmp(l3_proto.clone(), l4_proto, encap.clone(), registers);
+                }
             }
         }
     }
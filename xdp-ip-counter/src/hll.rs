@@ -0,0 +1,100 @@
+use xdp_ip_counter_common::{HllRegisters, HLL_REGISTERS};
+
+/// Hll is a HyperLogLog sketch estimating the number of unique sources added to it.
+/// Sketches are filled in by the ebpf program (one per CPU) and merged here.
+#[derive(Clone, Debug)]
+pub struct Hll {
+    registers: HllRegisters,
+}
+impl Hll {
+    pub fn new() -> Self {
+        Self {
+            registers: [0; HLL_REGISTERS],
+        }
+    }
+
+    /// Merges the registers of another sketch into this one, as if all its sources had been added to it.
+    pub fn merge(&mut self, registers: &HllRegisters) {
+        for (register, other) in self.registers.iter_mut().zip(registers.iter()) {
+            if *other > *register {
+                *register = *other;
+            }
+        }
+    }
+
+    pub fn registers(&self) -> &HllRegisters {
+        &self.registers
+    }
+
+    /// Returns the estimated number of unique sources added to the sketch.
+    pub fn estimate(&self) -> f64 {
+        let m = HLL_REGISTERS as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+
+        let mut sum = 0.0;
+        let mut zeros = 0;
+        for register in self.registers.iter() {
+            sum += 2f64.powi(-(*register as i32));
+            if *register == 0 {
+                zeros += 1;
+            }
+        }
+
+        let estimate = alpha * m * m / sum;
+        // Small range correction (linear counting), as the raw estimate is biased for low cardinalities
+        if estimate <= 2.5 * m && zeros > 0 {
+            m * (m / zeros as f64).ln()
+        } else {
+            estimate
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use xdp_ip_counter_common::{hash64, hll_position};
+
+    fn add(registers: &mut HllRegisters, source: u64) {
+        let (index, rank) = hll_position(hash64(source));
+        registers[index] = registers[index].max(rank);
+    }
+
+    #[test]
+    fn test_estimate() {
+        for count in [0u64, 10, 1_000, 100_000] {
+            let mut registers = [0; HLL_REGISTERS];
+            for source in 0..count {
+                add(&mut registers, source);
+            }
+            let mut hll = Hll::new();
+            hll.merge(&registers);
+
+            // Allowing three times the standard error
+            let error = (hll.estimate() - count as f64).abs();
+            assert!(
+                error <= count as f64 * 0.1,
+                "{} estimated as {}",
+                count,
+                hll.estimate()
+            );
+        }
+    }
+
+    #[test]
+    fn test_merge() {
+        // Two CPUs seeing overlapping sources
+        let (mut first, mut second) = ([0; HLL_REGISTERS], [0; HLL_REGISTERS]);
+        for source in 0..6_000 {
+            add(&mut first, source);
+        }
+        for source in 4_000..10_000 {
+            add(&mut second, source);
+        }
+        let mut hll = Hll::new();
+        hll.merge(&first);
+        hll.merge(&second);
+
+        assert!((hll.estimate() - 10_000.0).abs() <= 1_000.0);
+    }
+}
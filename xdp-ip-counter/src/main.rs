@@ -1,13 +1,23 @@
 #![feature(ip)]
 
 use clap::Parser;
-use log::info;
-use std::sync::{Arc, RwLock};
-use tokio::signal;
+use log::{info, warn};
+use std::sync::{Arc, Mutex, RwLock};
+use tokio::signal::{
+    self,
+    unix::{signal, SignalKind},
+};
 
 mod api;
 mod args;
+mod batch;
 mod ebpf;
+mod hll;
+mod netlink;
+mod pin;
+mod rcu;
+mod snapshot;
+mod storage;
 mod structs;
 
 #[tokio::main]
@@ -15,28 +25,114 @@ async fn main() -> Result<(), anyhow::Error> {
     env_logger::init();
 
     let args = args::Args::parse();
-    let ebpf = ebpf::init(&args);
-    let mut shared_maps = structs::SharedMaps::new(&ebpf);
-    let local_map = Arc::new(RwLock::new(structs::LocalMap::new()));
-
-    // Passing custom ports to ebpf side (if there are any)
+    // Subscribing before listing interfaces, so that none created in between is missed
+    let link_events = match netlink::LinkEvents::subscribe() {
+        Ok(link_events) => Some(link_events),
+        Err(err) => {
+            warn!(
+                "failed to subscribe to link events, new interfaces will not be attached: {}",
+                err
+            );
+            None
+        }
+    };
+    let bpf = ebpf::load(&args);
+    let mut shared_maps = structs::SharedMaps::new(&bpf);
+    // Passing the settings to ebpf side before attaching, so that no packet is seen without them
     shared_maps.add_custom_ports(args.parse_custom_ports())?;
+    shared_maps.set_decap(args.decap)?;
+    shared_maps.set_hll(args.hll)?;
+    shared_maps.set_ipv6_ext_max_depth(args.parse_ipv6_ext_max_depth())?;
+    let attachments = ebpf::attach(&args, bpf);
+    let mut local_map = structs::LocalMap::new(
+        &args.parse_windows(),
+        args.sliding,
+        args.parse_history_size(),
+    );
+    // Picking up where the previous run left off (if it was recent enough)
+    if let Some(path) = &args.snapshot {
+        match snapshot::load(path, args.parse_snapshot_max_age()) {
+            Ok(Some(snapshot)) => {
+                info!(
+                    "restoring the snapshot taken at {}",
+                    humantime::format_rfc3339_seconds(snapshot.taken)
+                );
+                local_map.restore(snapshot);
+            }
+            Ok(None) => info!("no recent snapshot to restore from {}", path),
+            Err(err) => warn!("failed to load the snapshot: {:#}", err),
+        }
+    }
+    for attachment in attachments.attached() {
+        if let Some(mode) = attachment.mode {
+            local_map.set_xdp_mode(&attachment.iface, mode);
+        }
+    }
+    let local_map = Arc::new(RwLock::new(local_map));
+    let attachments = Arc::new(Mutex::new(attachments));
+
+    if let Some(link_events) = link_events {
+        let attachments = attachments.clone();
+        let local_map = local_map.clone();
+        // Receiving link events blocks
+        std::thread::spawn(move || ebpf::follow_ifaces(attachments, link_events, local_map));
+    }
+
+    let storage = args.db.as_ref().map(|path| {
+        Arc::new(Mutex::new(
+            storage::Storage::open(path, args.parse_db_retention())
+                .expect("failed to open the database"),
+        ))
+    });
 
     tokio::spawn({
         let local_map = local_map.clone();
-        let aggregate_window = args.parse_window();
-        async move { ebpf::collect(&mut shared_maps, local_map, aggregate_window).await }
+        let sampling_interval = args.parse_sampling_interval();
+        let totals_max_idle = args.parse_totals_max_idle();
+        let map_capacity = args.parse_map_capacity();
+        let lru = args.lru;
+        let storage = storage.clone();
+        async move {
+            ebpf::collect(
+                &mut shared_maps,
+                local_map,
+                sampling_interval,
+                totals_max_idle,
+                map_capacity,
+                lru,
+                storage,
+            )
+            .await
+        }
     });
 
+    if let Some(path) = args.snapshot.clone() {
+        let local_map = local_map.clone();
+        let interval = args.parse_snapshot_interval();
+        tokio::spawn(async move { snapshot::run(local_map, path, interval).await });
+    }
+
     tokio::spawn({
         let local_map = local_map.clone();
         let server_port = args.parse_server_port();
         let serve_ip_list = args.serve_ip_list;
-        async move { api::server::serve(local_map, server_port, serve_ip_list).await }
+        async move { api::server::serve(local_map, storage, server_port, serve_ip_list).await }
     });
 
-    info!("Waiting for Ctrl-C...");
-    signal::ctrl_c().await?;
+    // Service managers and container runtimes stop processes with SIGTERM
+    let mut terminate = signal(SignalKind::terminate())?;
+    info!("Waiting for Ctrl-C or SIGTERM...");
+    tokio::select! {
+        res = signal::ctrl_c() => res?,
+        _ = terminate.recv() => {}
+    }
     println!("\nExiting...");
+    if let Some(path) = &args.snapshot {
+        snapshot::dump(&local_map, path);
+    }
+    attachments
+        .lock()
+        .expect("unable to accuire lock for attachments")
+        .detach_all();
     Ok(())
 }
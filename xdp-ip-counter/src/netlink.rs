@@ -0,0 +1,411 @@
+use std::{
+    collections::HashMap,
+    ffi::{CStr, CString},
+    io,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+};
+
+use crate::structs::XdpMode;
+
+// See include/uapi/linux/if_link.h
+const IFLA_XDP_ATTACHED: u16 = 2;
+const IFLA_XDP_PROG_ID: u16 = 4;
+const XDP_ATTACHED_DRV: u8 = 1;
+const XDP_ATTACHED_SKB: u8 = 2;
+const XDP_ATTACHED_HW: u8 = 3;
+// Nested and byte order flags of struct nlattr's type.
+const NLA_TYPE_MASK: u16 = !(1 << 15 | 1 << 14);
+
+const NLMSG_ERROR: u16 = libc::NLMSG_ERROR as u16;
+const NLMSG_HDR_LEN: usize = std::mem::size_of::<libc::nlmsghdr>();
+const IFINFOMSG_LEN: usize = std::mem::size_of::<IfInfoMsg>();
+
+/// struct ifinfomsg, the header of RTM_*LINK messages.
+#[repr(C)]
+#[derive(Default)]
+struct IfInfoMsg {
+    family: u8,
+    _pad: u8,
+    kind: u16,
+    index: i32,
+    flags: u32,
+    change: u32,
+}
+
+#[repr(C)]
+struct GetLink {
+    header: libc::nlmsghdr,
+    info: IfInfoMsg,
+}
+
+/// Returns the index of the interface named `iface`, or None if there is no such interface.
+pub fn ifindex(iface: &str) -> Option<u32> {
+    let name = CString::new(iface).ok()?;
+    match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+        0 => None,
+        ifindex => Some(ifindex),
+    }
+}
+
+/// IfaceNames resolves interface indexes to names, remembering them so that each is only looked up once.
+#[derive(Default)]
+pub struct IfaceNames(HashMap<u32, String>);
+impl IfaceNames {
+    /// Returns the name of interface `ifindex`, or its index if it no longer exists.
+    pub fn get(&mut self, ifindex: u32) -> String {
+        self.0
+            .entry(ifindex)
+            .or_insert_with(|| {
+                let mut name = [0 as libc::c_char; libc::IF_NAMESIZE];
+                match unsafe { libc::if_indextoname(ifindex, name.as_mut_ptr()) }.is_null() {
+                    true => ifindex.to_string(),
+                    false => unsafe { CStr::from_ptr(name.as_ptr()) }
+                        .to_string_lossy()
+                        .into_owned(),
+                }
+            })
+            .clone()
+    }
+}
+
+/// Returns the index and name of every network interface of the host.
+pub fn interfaces() -> io::Result<Vec<(u32, String)>> {
+    let list = unsafe { libc::if_nameindex() };
+    if list.is_null() {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut interfaces = Vec::new();
+    let mut entry = list;
+    // The list ends with an entry whose index is 0
+    while unsafe { (*entry).if_index } != 0 {
+        let name = unsafe { CStr::from_ptr((*entry).if_name) };
+        interfaces.push((
+            unsafe { (*entry).if_index },
+            name.to_string_lossy().into_owned(),
+        ));
+        entry = unsafe { entry.add(1) };
+    }
+    unsafe { libc::if_freenameindex(list) };
+    Ok(interfaces)
+}
+
+/// Returns whether interface `name` matches `pattern`, where * matches any characters and ? a single one.
+pub fn iface_matches(pattern: &str, name: &str) -> bool {
+    match pattern.chars().next() {
+        None => name.is_empty(),
+        Some('*') => {
+            let rest = &pattern[1..];
+            name.char_indices()
+                .map(|(i, _)| i)
+                .chain(std::iter::once(name.len()))
+                .any(|i| iface_matches(rest, &name[i..]))
+        }
+        Some(first) => match name.chars().next() {
+            Some(c) if first == '?' || first == c => {
+                iface_matches(&pattern[first.len_utf8()..], &name[c.len_utf8()..])
+            }
+            _ => false,
+        },
+    }
+}
+
+fn route_socket() -> io::Result<OwnedFd> {
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_NETLINK,
+            libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+            libc::NETLINK_ROUTE,
+        )
+    };
+    match fd {
+        fd if fd < 0 => Err(io::Error::last_os_error()),
+        fd => Ok(unsafe { OwnedFd::from_raw_fd(fd) }),
+    }
+}
+
+/// LinkEvent is an interface appearing or disappearing, as notified by rtnetlink.
+#[derive(Debug, PartialEq, Eq)]
+pub enum LinkEvent {
+    New { ifindex: u32, name: String },
+    Del { ifindex: u32, name: String },
+}
+
+/// LinkEvents is a netlink socket subscribed to the kernel's link notifications.
+pub struct LinkEvents(OwnedFd);
+impl LinkEvents {
+    /// Subscribes to link notifications. Interfaces created from then on are notified, even before the first recv().
+    pub fn subscribe() -> io::Result<Self> {
+        let socket = route_socket()?;
+        let mut addr: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as u16;
+        addr.nl_groups = libc::RTMGRP_LINK as u32;
+        let bound = unsafe {
+            libc::bind(
+                socket.as_raw_fd(),
+                &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+                std::mem::size_of::<libc::sockaddr_nl>() as u32,
+            )
+        };
+        if bound < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self(socket))
+    }
+
+    /// Blocks until the kernel sends notifications, then returns them.
+    /// Fails with ENOBUFS when notifications were dropped because they were not received fast enough.
+    pub fn recv(&self) -> io::Result<Vec<LinkEvent>> {
+        let mut buffer = vec![0u8; 32 * 1024];
+        let received = unsafe {
+            libc::recv(
+                self.0.as_raw_fd(),
+                buffer.as_mut_ptr() as *mut libc::c_void,
+                buffer.len(),
+                0,
+            )
+        };
+        if received < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(link_events(&buffer[..received as usize]))
+    }
+}
+
+/// Parses the RTM_NEWLINK and RTM_DELLINK messages in `data`.
+fn link_events(data: &[u8]) -> Vec<LinkEvent> {
+    messages(data)
+        .filter_map(|(kind, payload)| {
+            let ifindex = i32::from_ne_bytes(payload.get(4..8)?.try_into().unwrap()) as u32;
+            let (_, name) = attributes(payload.get(IFINFOMSG_LEN..)?)
+                .find(|(kind, _)| *kind == libc::IFLA_IFNAME)?;
+            let name = CStr::from_bytes_until_nul(name)
+                .ok()?
+                .to_string_lossy()
+                .into_owned();
+            match kind {
+                libc::RTM_NEWLINK => Some(LinkEvent::New { ifindex, name }),
+                libc::RTM_DELLINK => Some(LinkEvent::Del { ifindex, name }),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// Asks the kernel in which mode an XDP program is attached to interface `ifindex`.
+/// Returns None if there is no XDP program attached to it.
+pub fn xdp_mode(ifindex: u32) -> io::Result<Option<XdpMode>> {
+    Ok(link_xdp_mode(&get_link(ifindex)?))
+}
+
+/// Asks the kernel for the id of the XDP program attached to interface `ifindex`.
+/// Returns None if there is no XDP program attached to it.
+pub fn xdp_prog_id(ifindex: u32) -> io::Result<Option<u32>> {
+    Ok(link_xdp_prog_id(&get_link(ifindex)?))
+}
+
+/// Returns whether packets of interface `ifindex` start with an Ethernet header, unlike those of L3 devices such as tun,
+/// WireGuard or IP tunnels which start with their IP header.
+pub fn has_ethernet_header(ifindex: u32) -> io::Result<bool> {
+    Ok(matches!(
+        link_type(&get_link(ifindex)?),
+        Some(libc::ARPHRD_ETHER | libc::ARPHRD_LOOPBACK)
+    ))
+}
+
+/// Returns the payload of the RTM_NEWLINK message describing interface `ifindex`.
+fn get_link(ifindex: u32) -> io::Result<Vec<u8>> {
+    let socket = route_socket()?;
+    let request = GetLink {
+        header: libc::nlmsghdr {
+            nlmsg_len: std::mem::size_of::<GetLink>() as u32,
+            nlmsg_type: libc::RTM_GETLINK,
+            nlmsg_flags: libc::NLM_F_REQUEST as u16,
+            nlmsg_seq: 1,
+            nlmsg_pid: 0,
+        },
+        info: IfInfoMsg {
+            family: libc::AF_UNSPEC as u8,
+            index: ifindex as i32,
+            ..Default::default()
+        },
+    };
+    let sent = unsafe {
+        libc::send(
+            socket.as_raw_fd(),
+            &request as *const GetLink as *const libc::c_void,
+            std::mem::size_of::<GetLink>(),
+            0,
+        )
+    };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut buffer = vec![0u8; 32 * 1024];
+    let received = unsafe {
+        libc::recv(
+            socket.as_raw_fd(),
+            buffer.as_mut_ptr() as *mut libc::c_void,
+            buffer.len(),
+            0,
+        )
+    };
+    if received < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    for (kind, payload) in messages(&buffer[..received as usize]) {
+        match kind {
+            NLMSG_ERROR => {
+                // struct nlmsgerr starts with a negative errno
+                let errno = payload
+                    .get(..4)
+                    .map(|errno| i32::from_ne_bytes(errno.try_into().unwrap()))
+                    .unwrap_or(0);
+                return Err(io::Error::from_raw_os_error(-errno));
+            }
+            libc::RTM_NEWLINK => return Ok(payload.to_vec()),
+            _ => {}
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "no link in the netlink response",
+    ))
+}
+
+/// Returns the ARPHRD_* type of the link a RTM_NEWLINK message's payload describes.
+fn link_type(payload: &[u8]) -> Option<u16> {
+    Some(u16::from_ne_bytes(payload.get(2..4)?.try_into().unwrap()))
+}
+
+/// Returns the XDP mode carried in the IFLA_XDP attribute of a RTM_NEWLINK message's payload.
+fn link_xdp_mode(payload: &[u8]) -> Option<XdpMode> {
+    let (_, xdp) =
+        attributes(payload.get(IFINFOMSG_LEN..)?).find(|(kind, _)| *kind == libc::IFLA_XDP)?;
+    let (_, attached) = attributes(xdp).find(|(kind, _)| *kind == IFLA_XDP_ATTACHED)?;
+    match *attached.first()? {
+        XDP_ATTACHED_DRV => Some(XdpMode::Drv),
+        XDP_ATTACHED_SKB => Some(XdpMode::Skb),
+        XDP_ATTACHED_HW => Some(XdpMode::Hw),
+        // Not attached, or attached several times in different modes
+        _ => None,
+    }
+}
+
+/// Returns the id of the XDP program carried in the IFLA_XDP attribute of a RTM_NEWLINK message's payload.
+fn link_xdp_prog_id(payload: &[u8]) -> Option<u32> {
+    let (_, xdp) =
+        attributes(payload.get(IFINFOMSG_LEN..)?).find(|(kind, _)| *kind == libc::IFLA_XDP)?;
+    let (_, id) = attributes(xdp).find(|(kind, _)| *kind == IFLA_XDP_PROG_ID)?;
+    Some(u32::from_ne_bytes(id.get(..4)?.try_into().unwrap()))
+}
+
+/// Iterates over the (type, payload) of the netlink messages in `data`.
+fn messages(mut data: &[u8]) -> impl Iterator<Item = (u16, &[u8])> {
+    std::iter::from_fn(move || {
+        let len = u32::from_ne_bytes(data.get(..4)?.try_into().unwrap()) as usize;
+        let kind = u16::from_ne_bytes(data.get(4..6)?.try_into().unwrap());
+        let payload = data.get(NLMSG_HDR_LEN..len)?;
+        data = data.get(align(len)..).unwrap_or_default();
+        Some((kind, payload))
+    })
+}
+
+/// Iterates over the (type, payload) of the netlink attributes in `data`.
+fn attributes(mut data: &[u8]) -> impl Iterator<Item = (u16, &[u8])> {
+    std::iter::from_fn(move || {
+        let len = u16::from_ne_bytes(data.get(..2)?.try_into().unwrap()) as usize;
+        let kind = u16::from_ne_bytes(data.get(2..4)?.try_into().unwrap()) & NLA_TYPE_MASK;
+        let payload = data.get(4..len)?;
+        data = data.get(align(len)..).unwrap_or_default();
+        Some((kind, payload))
+    })
+}
+
+/// Rounds `len` up to the 4 bytes netlink messages and attributes are aligned to.
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn attribute(kind: u16, payload: &[u8]) -> Vec<u8> {
+        let mut attribute = ((4 + payload.len()) as u16).to_ne_bytes().to_vec();
+        attribute.extend(kind.to_ne_bytes());
+        attribute.extend(payload);
+        attribute.resize(align(attribute.len()), 0);
+        attribute
+    }
+
+    #[test]
+    fn test_iface_matches() {
+        assert!(iface_matches("eth0", "eth0"));
+        assert!(!iface_matches("eth0", "eth01"));
+        assert!(iface_matches("veth*", "veth"));
+        assert!(iface_matches("veth*", "veth3f2a1b"));
+        assert!(!iface_matches("veth*", "eth0"));
+        assert!(iface_matches("eth?", "eth1"));
+        assert!(!iface_matches("eth?", "eth10"));
+        assert!(iface_matches("*.100", "bond0.100"));
+    }
+
+    fn message(kind: u16, ifindex: i32, attributes: &[u8]) -> Vec<u8> {
+        let len = NLMSG_HDR_LEN + IFINFOMSG_LEN + attributes.len();
+        let mut message = (len as u32).to_ne_bytes().to_vec();
+        message.extend(kind.to_ne_bytes());
+        message.resize(NLMSG_HDR_LEN + 4, 0);
+        message.extend(ifindex.to_ne_bytes());
+        message.resize(NLMSG_HDR_LEN + IFINFOMSG_LEN, 0);
+        message.extend(attributes);
+        message
+    }
+
+    #[test]
+    fn test_link_events() {
+        let mut data = message(
+            libc::RTM_NEWLINK,
+            7,
+            &attribute(libc::IFLA_IFNAME, b"veth1a\0"),
+        );
+        data.extend(message(
+            libc::RTM_DELLINK,
+            5,
+            &attribute(libc::IFLA_IFNAME, b"eth1\0"),
+        ));
+        // Other messages, and links without a name, are skipped
+        data.extend(message(libc::RTM_NEWADDR, 7, &[]));
+        data.extend(message(libc::RTM_NEWLINK, 8, &[]));
+        assert_eq!(
+            link_events(&data),
+            vec![
+                LinkEvent::New {
+                    ifindex: 7,
+                    name: "veth1a".to_string()
+                },
+                LinkEvent::Del {
+                    ifindex: 5,
+                    name: "eth1".to_string()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_link_xdp_mode() {
+        let mut payload = vec![0u8; IFINFOMSG_LEN];
+        payload.extend(attribute(libc::IFLA_IFNAME, b"eth0\0"));
+        assert_eq!(link_xdp_mode(&payload), None);
+        assert_eq!(link_xdp_prog_id(&payload), None);
+
+        let mut xdp = attribute(IFLA_XDP_ATTACHED, &[XDP_ATTACHED_DRV]);
+        xdp.extend(attribute(IFLA_XDP_PROG_ID, &42u32.to_ne_bytes()));
+        // Nested attribute
+        payload.extend(attribute(libc::IFLA_XDP | 1 << 15, &xdp));
+        assert_eq!(link_xdp_mode(&payload), Some(XdpMode::Drv));
+        assert_eq!(link_xdp_prog_id(&payload), Some(42));
+    }
+}
@@ -0,0 +1,217 @@
+use anyhow::{bail, Context};
+use aya::programs::{ProgramFd, Xdp, XdpFlags};
+use log::info;
+
+use crate::netlink;
+use std::{
+    ffi::CString,
+    fs, io,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    path::{Path, PathBuf},
+};
+
+/// Directory of bpffs the program, its links and maps are pinned to with --pin.
+pub const PIN_PATH: &str = "/sys/fs/bpf/xdp-ip-counter";
+
+// See include/uapi/linux/bpf.h
+const BPF_OBJ_PIN: libc::c_int = 6;
+const BPF_OBJ_GET: libc::c_int = 7;
+const BPF_OBJ_GET_INFO_BY_FD: libc::c_int = 15;
+const BPF_LINK_CREATE: libc::c_int = 28;
+const BPF_XDP: u32 = 37;
+
+/// The BPF_OBJ_PIN and BPF_OBJ_GET part of union bpf_attr.
+#[repr(C)]
+struct ObjAttr {
+    pathname: u64,
+    bpf_fd: u32,
+    file_flags: u32,
+}
+
+/// The BPF_OBJ_GET_INFO_BY_FD part of union bpf_attr.
+#[repr(C)]
+struct InfoAttr {
+    bpf_fd: u32,
+    info_len: u32,
+    info: u64,
+}
+
+/// The BPF_LINK_CREATE part of union bpf_attr.
+#[repr(C)]
+struct LinkCreateAttr {
+    prog_fd: u32,
+    target_ifindex: u32,
+    attach_type: u32,
+    flags: u32,
+}
+
+/// Runs the bpf() syscall `cmd` with `attr`, the part of union bpf_attr it takes. Returns what the syscall returned.
+pub fn bpf<T>(cmd: libc::c_int, attr: &T) -> io::Result<RawFd> {
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_bpf,
+            cmd,
+            attr as *const T,
+            std::mem::size_of::<T>(),
+        )
+    };
+    match ret {
+        ret if ret < 0 => Err(io::Error::last_os_error()),
+        fd => Ok(fd as RawFd),
+    }
+}
+
+/// Pins the bpf object `fd` refers to at `path`.
+fn pin_fd(fd: RawFd, path: &Path) -> Result<(), anyhow::Error> {
+    let pathname = CString::new(path.to_string_lossy().into_owned())?;
+    bpf(
+        BPF_OBJ_PIN,
+        &ObjAttr {
+            pathname: pathname.as_ptr() as u64,
+            bpf_fd: fd as u32,
+            file_flags: 0,
+        },
+    )
+    .with_context(|| format!("failed to pin {}", path.display()))?;
+    Ok(())
+}
+
+/// Opens the bpf object pinned at `path`.
+fn obj_get(path: &Path) -> Result<OwnedFd, anyhow::Error> {
+    let pathname = CString::new(path.to_string_lossy().into_owned())?;
+    let fd = bpf(
+        BPF_OBJ_GET,
+        &ObjAttr {
+            pathname: pathname.as_ptr() as u64,
+            bpf_fd: 0,
+            file_flags: 0,
+        },
+    )
+    .with_context(|| format!("failed to open {}", path.display()))?;
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// Returns the id of the bpf program `fd` refers to.
+fn prog_id(fd: &OwnedFd) -> io::Result<u32> {
+    // struct bpf_prog_info starts with the program's type and id, the kernel fills in no more than asked for
+    let mut info = [0u32; 2];
+    bpf(
+        BPF_OBJ_GET_INFO_BY_FD,
+        &InfoAttr {
+            bpf_fd: fd.as_raw_fd() as u32,
+            info_len: std::mem::size_of_val(&info) as u32,
+            info: info.as_mut_ptr() as u64,
+        },
+    )?;
+    Ok(info[1])
+}
+
+/// Returns the type and capacity of the map pinned at `path`, if there is one.
+pub fn pinned_map_def(path: &Path) -> Result<Option<(u32, u32)>, anyhow::Error> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let fd = obj_get(path)?;
+    // struct bpf_map_info starts with the map's type, id, key_size, value_size and max_entries
+    let mut info = [0u32; 5];
+    bpf(
+        BPF_OBJ_GET_INFO_BY_FD,
+        &InfoAttr {
+            bpf_fd: fd.as_raw_fd() as u32,
+            info_len: std::mem::size_of_val(&info) as u32,
+            info: info.as_mut_ptr() as u64,
+        },
+    )
+    .with_context(|| format!("failed to get the definition of {}", path.display()))?;
+    Ok(Some((info[0], info[4])))
+}
+
+/// Returns where the program is pinned.
+fn prog_path() -> PathBuf {
+    Path::new(PIN_PATH).join("xdp_ip_counter")
+}
+
+/// Returns where the link attaching the program to `iface` is pinned.
+pub fn link_path(iface: &str) -> PathBuf {
+    Path::new(PIN_PATH).join(format!("link_{}", iface))
+}
+
+/// Attaches the program to `iface` with a link pinned to bpffs, so that it stays attached once the process exits.
+/// The program itself is pinned too: as long as a previous run's one is, it is the one attached (and `program` is not
+/// loaded), so that all interfaces share a single program. If the link already exists, it is kept as is.
+/// Removing the link's pin detaches it.
+pub fn attach(program: &mut Xdp, iface: &str, flags: XdpFlags) -> Result<(), anyhow::Error> {
+    let ifindex =
+        netlink::ifindex(iface).with_context(|| format!("unknown interface {}", iface))?;
+    let prog = prog_path();
+    let pinned_prog = match prog.exists() {
+        true => Some(obj_get(&prog)?),
+        false => None,
+    };
+    let link = link_path(iface);
+    if link.exists() {
+        let attached = netlink::xdp_prog_id(ifindex)
+            .with_context(|| format!("failed to get the XDP program attached to {}", iface))?;
+        let pinned_id = match &pinned_prog {
+            Some(fd) => Some(
+                prog_id(fd)
+                    .with_context(|| format!("failed to get the id of {}", prog.display()))?,
+            ),
+            None => None,
+        };
+        match attached {
+            Some(id) if Some(id) == pinned_id => {
+                info!("reusing the program pinned to {}", link.display());
+                return Ok(());
+            }
+            // The link of an interface that has since been deleted and recreated no longer attaches anything
+            None => {
+                info!("replacing the stale link pinned to {}", link.display());
+                fs::remove_file(&link)?;
+            }
+            Some(id) => bail!(
+                "another XDP program (id {}) is attached to {} instead of the pinned one, detach it or remove {}",
+                id,
+                iface,
+                link.display()
+            ),
+        }
+    }
+
+    let prog_fd = match (&pinned_prog, program.fd()) {
+        (Some(fd), _) => fd.as_raw_fd(),
+        (None, Some(prog_fd)) => prog_fd,
+        (None, None) => {
+            program.load()?;
+            let prog_fd = program.fd().context("the program is not loaded")?;
+            pin_fd(prog_fd, &prog)?;
+            prog_fd
+        }
+    };
+
+    let link_fd = bpf(
+        BPF_LINK_CREATE,
+        &LinkCreateAttr {
+            prog_fd: prog_fd as u32,
+            target_ifindex: ifindex,
+            attach_type: BPF_XDP,
+            flags: flags.bits(),
+        },
+    )
+    .context("failed to attach the XDP program with a link (requires Linux 5.9)")?;
+
+    let pinned = pin_fd(link_fd, &link);
+    // The pin holds its own reference to the link
+    unsafe { libc::close(link_fd) };
+    pinned?;
+
+    Ok(())
+}
+
+/// Detaches the program from `iface` by removing the pin of its link.
+pub fn detach(iface: &str) -> io::Result<()> {
+    match fs::remove_file(link_path(iface)) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
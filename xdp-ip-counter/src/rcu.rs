@@ -0,0 +1,81 @@
+use std::{
+    io,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+};
+
+use crate::pin::bpf;
+
+// See include/uapi/linux/bpf.h
+const BPF_MAP_CREATE: libc::c_int = 0;
+const BPF_MAP_UPDATE_ELEM: libc::c_int = 2;
+const BPF_MAP_TYPE_ARRAY: u32 = 2;
+const BPF_MAP_TYPE_ARRAY_OF_MAPS: u32 = 12;
+
+/// The BPF_MAP_CREATE part of union bpf_attr.
+#[repr(C)]
+struct MapCreateAttr {
+    map_type: u32,
+    key_size: u32,
+    value_size: u32,
+    max_entries: u32,
+    map_flags: u32,
+    inner_map_fd: u32,
+}
+
+/// The BPF_MAP_UPDATE_ELEM part of union bpf_attr.
+#[repr(C)]
+struct MapUpdateAttr {
+    map_fd: u32,
+    _pad: u32,
+    key: u64,
+    value: u64,
+    flags: u64,
+}
+
+/// Barrier waits for the ebpf program runs in flight to complete, i.e. for an RCU grace period.
+/// It relies on the kernel calling synchronize_rcu() when an element of a map of maps is updated, so that user-space
+/// knows running programs no longer use the inner map it replaced.
+pub struct Barrier {
+    outer: OwnedFd,
+    inner: OwnedFd,
+}
+impl Barrier {
+    pub fn new() -> io::Result<Self> {
+        let inner = map_create(BPF_MAP_TYPE_ARRAY, 0)?;
+        let outer = map_create(BPF_MAP_TYPE_ARRAY_OF_MAPS, inner.as_raw_fd() as u32)?;
+        Ok(Self { outer, inner })
+    }
+
+    /// Blocks until the ebpf program runs started before the call have completed, which usually takes milliseconds.
+    pub fn wait(&self) -> io::Result<()> {
+        let key: u32 = 0;
+        let value = self.inner.as_raw_fd() as u32;
+        bpf(
+            BPF_MAP_UPDATE_ELEM,
+            &MapUpdateAttr {
+                map_fd: self.outer.as_raw_fd() as u32,
+                _pad: 0,
+                key: &key as *const u32 as u64,
+                value: &value as *const u32 as u64,
+                flags: 0,
+            },
+        )?;
+        Ok(())
+    }
+}
+
+/// Creates a single entry array of u32 or, given `inner_map_fd`, of such arrays.
+fn map_create(map_type: u32, inner_map_fd: u32) -> io::Result<OwnedFd> {
+    let fd = bpf(
+        BPF_MAP_CREATE,
+        &MapCreateAttr {
+            map_type,
+            key_size: 4,
+            value_size: 4,
+            max_entries: 1,
+            map_flags: 0,
+            inner_map_fd,
+        },
+    )?;
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
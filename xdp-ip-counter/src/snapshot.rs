@@ -0,0 +1,107 @@
+use anyhow::Context;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    io::ErrorKind,
+    net::IpAddr,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use crate::structs::{Encap, HistoryRow, L3Proto, L4Proto, LocalMap, Traffic};
+
+/// An IP's traffic in an IpMap, flattened so that it can be written to a file.
+pub type IpRecord = (L3Proto, L4Proto, IpAddr, Encap, Traffic);
+/// A sketch of a Sketches map, flattened so that it can be written to a file.
+pub type SketchRecord = (L3Proto, L4Proto, Encap, Vec<u8>);
+
+/// Snapshot holds what a LocalMap needs to carry on where it left off after a restart.
+/// Data read from the ebpf maps after the last sample is not part of it.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Snapshot {
+    pub taken: SystemTime,
+    pub windows: Vec<WindowSnapshot>,
+    pub totals: Vec<(L3Proto, L4Proto, Encap, Traffic)>,
+    pub history: Vec<HistoryRow>,
+}
+
+/// WindowSnapshot holds the published and partial data of a Window, see Window for the fields.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct WindowSnapshot {
+    pub seconds: u64,
+    pub sliding: bool,
+    pub elapsed: u64,
+    pub since_complete: u64,
+    pub aggr: Vec<IpRecord>,
+    pub sketches_aggr: Vec<SketchRecord>,
+    pub tmp: Vec<IpRecord>,
+    pub sketches_tmp: Vec<SketchRecord>,
+    pub ring: Vec<(u64, Vec<IpRecord>, Vec<SketchRecord>)>,
+}
+
+/// Writes `snapshot` to `path`, replacing the previous one only once it has been entirely written.
+pub fn save(path: &str, snapshot: &Snapshot) -> Result<(), anyhow::Error> {
+    let tmp = format!("{}.tmp", path);
+    fs::write(&tmp, serde_json::to_vec(snapshot)?)
+        .with_context(|| format!("failed to write {}", tmp))?;
+    fs::rename(&tmp, path).with_context(|| format!("failed to rename {} to {}", tmp, path))?;
+    Ok(())
+}
+
+/// Reads the snapshot at `path`. Returns None if there is none, or if it was taken more than `max_age` ago.
+pub fn load(path: &str, max_age: Duration) -> Result<Option<Snapshot>, anyhow::Error> {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err).with_context(|| format!("failed to read {}", path)),
+    };
+    let snapshot: Snapshot =
+        serde_json::from_slice(&data).with_context(|| format!("failed to parse {}", path))?;
+
+    match SystemTime::now().duration_since(snapshot.taken) {
+        Ok(age) if age > max_age => Ok(None),
+        _ => Ok(Some(snapshot)),
+    }
+}
+
+/// Saves local_map to `path` every `interval`.
+pub async fn run(local_map: Arc<RwLock<LocalMap>>, path: String, interval: Duration) {
+    loop {
+        tokio::time::sleep(interval).await;
+        dump(&local_map, &path);
+    }
+}
+
+/// Saves local_map to `path`, logging failures.
+pub fn dump(local_map: &Arc<RwLock<LocalMap>>, path: &str) {
+    // Only copy the data under the lock, writing may take a while.
+    let snapshot = local_map
+        .read()
+        .expect("unable to accuire lock for local_map")
+        .snapshot();
+    if let Err(err) = save(path, &snapshot) {
+        warn!("failed to save the snapshot: {:#}", err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_missing_or_stale() {
+        let path =
+            std::env::temp_dir().join(format!("xdp-ip-counter-{}.snapshot", std::process::id()));
+        let path = path.to_str().unwrap();
+        assert!(load(path, Duration::from_secs(60)).unwrap().is_none());
+
+        let mut snapshot = LocalMap::new(&[10], false, 0).snapshot();
+        snapshot.taken -= Duration::from_secs(120);
+        save(path, &snapshot).unwrap();
+        assert!(load(path, Duration::from_secs(60)).unwrap().is_none());
+        assert!(load(path, Duration::from_secs(600)).unwrap().is_some());
+
+        fs::remove_file(path).unwrap();
+    }
+}
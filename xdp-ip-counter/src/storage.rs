@@ -0,0 +1,337 @@
+use rusqlite::{params, params_from_iter, types::Value, Connection};
+use serde::Serialize;
+use std::{
+    net::IpAddr,
+    time::{Duration, SystemTime},
+};
+
+use crate::structs::{serialize_rfc3339, IpMap};
+
+/// Filter narrows down the persisted IPs returned by Storage::query(). Unset fields match everything.
+#[derive(Default, Debug)]
+pub struct Filter {
+    pub ip: Option<IpAddr>,
+    pub port: Option<u16>,
+    pub window: Option<String>,
+    /// Only IPs seen at or after this time.
+    pub from: Option<SystemTime>,
+    /// Only IPs seen at or before this time.
+    pub to: Option<SystemTime>,
+}
+
+/// StoredIp is an entry of the /query and /ip endpoints: an IP's activity on a port over all matching windows.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct StoredIp {
+    ip: String,
+    window: String,
+    network: String,
+    transport: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    port: Option<u16>,
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    icmp_type: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    iface: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    vlan: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tunnel: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    vni: Option<u32>,
+    packets: u64,
+    bytes: u64,
+    #[serde(
+        serialize_with = "serialize_rfc3339",
+        skip_serializing_if = "Option::is_none"
+    )]
+    first_seen: Option<SystemTime>,
+    #[serde(
+        serialize_with = "serialize_rfc3339",
+        skip_serializing_if = "Option::is_none"
+    )]
+    last_seen: Option<SystemTime>,
+}
+
+/// Storage persists the IPs of completed windows to a SQLite database, so they outlive restarts.
+pub struct Storage {
+    conn: Connection,
+    retention: Duration,
+}
+impl Storage {
+    /// Opens (or creates) the database at `path`. Rows older than `retention` are deleted as new ones come in.
+    pub fn open(path: &str, retention: Duration) -> Result<Self, rusqlite::Error> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS ips (
+                timestamp INTEGER NOT NULL,
+                window TEXT NOT NULL,
+                ip TEXT NOT NULL,
+                network TEXT NOT NULL,
+                transport TEXT NOT NULL,
+                port INTEGER,
+                type INTEGER,
+                code INTEGER,
+                iface TEXT,
+                vlan INTEGER,
+                tunnel TEXT,
+                vni INTEGER,
+                packets INTEGER NOT NULL,
+                bytes INTEGER NOT NULL,
+                first_seen INTEGER,
+                last_seen INTEGER
+            );
+            CREATE INDEX IF NOT EXISTS ips_ip ON ips (ip, timestamp);
+            CREATE INDEX IF NOT EXISTS ips_port ON ips (port, timestamp);
+            CREATE INDEX IF NOT EXISTS ips_timestamp ON ips (timestamp);",
+        )?;
+
+        Ok(Self { conn, retention })
+    }
+
+    /// Writes the IPs of the window labelled `window`, as of `timestamp`, then deletes rows past the retention period.
+    /// All times are stored as milliseconds since the Unix epoch.
+    pub fn store(
+        &mut self,
+        timestamp: SystemTime,
+        window: &str,
+        ips: &IpMap,
+    ) -> Result<(), rusqlite::Error> {
+        let tx = self.conn.transaction()?;
+        {
+            let mut insert = tx.prepare_cached(
+                "INSERT INTO ips (timestamp, window, ip, network, transport, port, type, code, iface, vlan, tunnel, vni, packets, bytes, first_seen, last_seen)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16)",
+            )?;
+            for (l3_proto, l4_map) in ips {
+                for (l4_proto, ips) in l4_map {
+                    let icmp = l4_proto.icmp();
+                    for (ip_item, traffic) in ips {
+                        let encap = ip_item.encap();
+                        insert.execute(params![
+                            unix_millis(timestamp),
+                            window,
+                            ip_item.ip().to_string(),
+                            l3_proto.to_string(),
+                            l4_proto.to_string(),
+                            l4_proto.port(),
+                            icmp.map(|(icmp_type, _)| icmp_type),
+                            icmp.map(|(_, code)| code),
+                            encap.iface(),
+                            encap.vlan(),
+                            encap.tunnel().map(|tunnel| tunnel.to_string()),
+                            encap.vni(),
+                            traffic.packets as i64,
+                            traffic.bytes as i64,
+                            traffic.first_seen.map(unix_millis),
+                            traffic.last_seen.map(unix_millis),
+                        ])?;
+                    }
+                }
+            }
+        }
+        tx.execute(
+            "DELETE FROM ips WHERE timestamp < ?1",
+            params![unix_millis(timestamp - self.retention)],
+        )?;
+        tx.commit()
+    }
+}
+
+impl Storage {
+    /// Returns what persisted IPs matching `filter` have sent to each port, oldest activity first.
+    pub fn query(&self, filter: &Filter) -> Result<Vec<StoredIp>, rusqlite::Error> {
+        let mut conditions: Vec<&str> = vec![];
+        let mut values: Vec<Value> = vec![];
+        if let Some(ip) = filter.ip {
+            conditions.push("ip = ?");
+            values.push(Value::Text(ip.to_string()));
+        }
+        if let Some(port) = filter.port {
+            conditions.push("port = ?");
+            values.push(Value::Integer(port as i64));
+        }
+        if let Some(window) = &filter.window {
+            conditions.push("window = ?");
+            values.push(Value::Text(window.clone()));
+        }
+        if let Some(from) = filter.from {
+            conditions.push("COALESCE(last_seen, timestamp) >= ?");
+            values.push(Value::Integer(unix_millis(from)));
+        }
+        if let Some(to) = filter.to {
+            conditions.push("COALESCE(first_seen, timestamp) <= ?");
+            values.push(Value::Integer(unix_millis(to)));
+        }
+        let conditions = match conditions.len() {
+            0 => String::new(),
+            _ => format!("WHERE {}", conditions.join(" AND ")),
+        };
+
+        let mut select = self.conn.prepare(&format!(
+            "SELECT ip, window, network, transport, port, type, code, iface, vlan, tunnel, vni,
+                SUM(packets), SUM(bytes), MIN(first_seen), MAX(last_seen)
+            FROM ips {}
+            GROUP BY ip, window, network, transport, port, type, code, iface, vlan, tunnel, vni
+            ORDER BY MIN(COALESCE(first_seen, timestamp))",
+            conditions
+        ))?;
+        let rows = select.query_map(params_from_iter(values), |row| {
+            Ok(StoredIp {
+                ip: row.get(0)?,
+                window: row.get(1)?,
+                network: row.get(2)?,
+                transport: row.get(3)?,
+                port: row.get(4)?,
+                icmp_type: row.get(5)?,
+                code: row.get(6)?,
+                iface: row.get(7)?,
+                vlan: row.get(8)?,
+                tunnel: row.get(9)?,
+                vni: row.get(10)?,
+                packets: row.get::<_, i64>(11)? as u64,
+                bytes: row.get::<_, i64>(12)? as u64,
+                first_seen: row.get::<_, Option<i64>>(13)?.map(from_unix_millis),
+                last_seen: row.get::<_, Option<i64>>(14)?.map(from_unix_millis),
+            })
+        })?;
+        rows.collect()
+    }
+}
+
+pub fn unix_millis(time: SystemTime) -> i64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as i64
+}
+
+fn from_unix_millis(millis: i64) -> SystemTime {
+    SystemTime::UNIX_EPOCH + Duration::from_millis(millis as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structs::{Encap, L3Proto, L4Proto, LocalMap, Traffic};
+    use xdp_ip_counter_common::FlowStats;
+
+    fn count(storage: &Storage) -> i64 {
+        storage
+            .conn
+            .query_row("SELECT COUNT(*) FROM ips", [], |row| row.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_store_and_retention() {
+        let mut storage = Storage::open(":memory:", Duration::from_secs(3600)).unwrap();
+        let mut local_map = LocalMap::new(&[10], false, 0);
+        for ip in [[8, 8, 8, 8], [1, 1, 1, 1]] {
+            local_map.add_tmp(
+                L3Proto::Ipv4,
+                L4Proto::Tcp(5432),
+                ip,
+                Encap::new(100, 0, 0).with_iface("eth0".to_string()),
+                Traffic::default(),
+            );
+        }
+        local_map.aggr(10);
+
+        let now = SystemTime::now();
+        let window = &local_map.get_windows()[0];
+        storage
+            .store(
+                now - Duration::from_secs(7200),
+                window.label(),
+                window.get_ips(),
+            )
+            .unwrap();
+        assert_eq!(count(&storage), 2);
+
+        // Storing newer rows prunes the ones past the retention period
+        storage
+            .store(now, window.label(), window.get_ips())
+            .unwrap();
+        assert_eq!(count(&storage), 2);
+        let (port, iface, vlan): (u16, String, u16) = storage
+            .conn
+            .query_row(
+                "SELECT port, iface, vlan FROM ips WHERE ip = '8.8.8.8'",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .unwrap();
+        assert_eq!((port, iface.as_str(), vlan), (5432, "eth0", 100));
+    }
+
+    #[test]
+    fn test_query() {
+        let mut storage = Storage::open(":memory:", Duration::from_secs(3600)).unwrap();
+        let mut local_map = LocalMap::new(&[10], false, 0);
+        let boot_time = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        for (ip, port, seen) in [
+            ([8, 8, 8, 8], 5432, 10),
+            ([8, 8, 8, 8], 22, 20),
+            ([1, 1, 1, 1], 5432, 30),
+        ] {
+            let seen = Duration::from_secs(seen).as_nanos() as u64;
+            local_map.add_tmp(
+                L3Proto::Ipv4,
+                L4Proto::Tcp(port),
+                ip,
+                Encap::default(),
+                Traffic::new(
+                    &FlowStats {
+                        packets: 1,
+                        bytes: 60,
+                        first_seen: seen,
+                        last_seen: seen,
+                    },
+                    boot_time,
+                ),
+            );
+        }
+        local_map.aggr(10);
+        let window = &local_map.get_windows()[0];
+        storage
+            .store(SystemTime::now(), window.label(), window.get_ips())
+            .unwrap();
+        storage
+            .store(SystemTime::now(), window.label(), window.get_ips())
+            .unwrap();
+
+        // Who talked to 5432, with both windows merged
+        let rows = storage
+            .query(&Filter {
+                port: Some(5432),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].ip, "8.8.8.8");
+        assert_eq!(rows[0].packets, 2);
+
+        // ... between T1 and T2
+        let rows = storage
+            .query(&Filter {
+                port: Some(5432),
+                from: Some(boot_time + Duration::from_secs(25)),
+                to: Some(boot_time + Duration::from_secs(35)),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].ip, "1.1.1.1");
+
+        // Every port an address touched
+        let rows = storage
+            .query(&Filter {
+                ip: Some(IpAddr::from([8, 8, 8, 8])),
+                ..Default::default()
+            })
+            .unwrap();
+        let ports: Vec<Option<u16>> = rows.iter().map(|row| row.port).collect();
+        assert_eq!(ports, vec![Some(5432), Some(22)]);
+    }
+}
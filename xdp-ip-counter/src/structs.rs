@@ -1,17 +1,105 @@
 use aya::{
     maps::{self, MapRefMut},
+    programs::XdpFlags,
     Bpf,
 };
-use log::info;
-use serde::Serialize;
+use log::{info, warn};
+use network_types::ip::IpProto;
+use serde::{Deserialize, Serialize};
+
+use crate::batch;
+use crate::hll::Hll;
+use crate::snapshot::{IpRecord, SketchRecord, Snapshot, WindowSnapshot};
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
     fmt::Display,
     net::IpAddr,
+    os::unix::io::RawFd,
+    time::{Duration, SystemTime},
+};
+use xdp_ip_counter_common::{
+    FlowMeta, FlowStats, FlowV4, FlowV6, HllKey, HllRegisters, COUNTER_INSERT_FAILURES_HLL,
+    COUNTER_INSERT_FAILURES_ICMP_V4, COUNTER_INSERT_FAILURES_ICMP_V6,
+    COUNTER_INSERT_FAILURES_IP_V4, COUNTER_INSERT_FAILURES_IP_V6, COUNTER_IPV4_FRAGMENTS,
+    COUNTER_IPV4_OPTIONS, COUNTER_IPV6_EXT_TOO_DEEP, COUNTER_IPV6_EXT_TRUNCATED,
+    COUNTER_IPV6_FRAGMENTS, TUNNEL_6IN4, TUNNEL_GENEVE, TUNNEL_GRE, TUNNEL_IPIP, TUNNEL_VXLAN,
 };
 
+/// Counter describes a slot of the ebpf COUNTERS map and how it is exposed as a prometheus metric.
+pub struct Counter {
+    pub index: u32,
+    pub name: &'static str,
+    pub help: &'static str,
+    /// Extra prometheus labels, e.g. `reason="..."`. Empty if the metric has no labels.
+    pub labels: &'static str,
+}
+
+/// All counters exported by the ebpf program. Counters sharing a name must be adjacent.
+pub const COUNTERS: &[Counter] = &[
+    Counter {
+        index: COUNTER_IPV4_OPTIONS,
+        name: "ipv4_options_packets_total",
+        help: "Number of IPv4 packets carrying options seen.",
+        labels: "",
+    },
+    Counter {
+        index: COUNTER_IPV6_EXT_TOO_DEEP,
+        name: "ipv6_ext_header_errors_total",
+        help: "Number of IPv6 packets not counted because of their extension header chain.",
+        labels: "reason=\"too_deep\"",
+    },
+    Counter {
+        index: COUNTER_IPV6_EXT_TRUNCATED,
+        name: "ipv6_ext_header_errors_total",
+        help: "Number of IPv6 packets not counted because of their extension header chain.",
+        labels: "reason=\"truncated\"",
+    },
+    Counter {
+        index: COUNTER_IPV4_FRAGMENTS,
+        name: "fragments_total",
+        help: "Number of non-first IP fragments seen. They are not attributed to a port.",
+        labels: "network=\"IPv4\"",
+    },
+    Counter {
+        index: COUNTER_IPV6_FRAGMENTS,
+        name: "fragments_total",
+        help: "Number of non-first IP fragments seen. They are not attributed to a port.",
+        labels: "network=\"IPv6\"",
+    },
+    Counter {
+        index: COUNTER_INSERT_FAILURES_IP_V4,
+        name: "map_insert_failures_total",
+        help: "Number of flows not recorded because the ebpf map was full.",
+        labels: "map=\"IP_V4\"",
+    },
+    Counter {
+        index: COUNTER_INSERT_FAILURES_IP_V6,
+        name: "map_insert_failures_total",
+        help: "Number of flows not recorded because the ebpf map was full.",
+        labels: "map=\"IP_V6\"",
+    },
+    Counter {
+        index: COUNTER_INSERT_FAILURES_ICMP_V4,
+        name: "map_insert_failures_total",
+        help: "Number of flows not recorded because the ebpf map was full.",
+        labels: "map=\"ICMP_V4\"",
+    },
+    Counter {
+        index: COUNTER_INSERT_FAILURES_ICMP_V6,
+        name: "map_insert_failures_total",
+        help: "Number of flows not recorded because the ebpf map was full.",
+        labels: "map=\"ICMP_V6\"",
+    },
+    Counter {
+        index: COUNTER_INSERT_FAILURES_HLL,
+        name: "map_insert_failures_total",
+        help: "Number of flows not recorded because the ebpf map was full.",
+        labels: "map=\"HLL\"",
+    },
+];
+
 /// L3Proto represents the layer 3 protocol of a packet.
-#[derive(PartialEq, Eq, Hash, Clone, Serialize, Debug)]
+#[derive(PartialEq, Eq, Hash, Clone, Serialize, Debug, Deserialize)]
 #[non_exhaustive]
 pub enum L3Proto {
     #[serde(rename = "IPv4")]
@@ -28,21 +116,257 @@ impl Display for L3Proto {
     }
 }
 
-/// L4Proto represents the layer 4 protocol of a packet and the port number.  
-#[derive(PartialEq, Eq, Hash, Clone, Copy, Serialize, Debug)]
+/// L4Proto represents the layer 4 protocol of a packet and the port number (or ICMP type and code).
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Serialize, Debug, Deserialize)]
 #[non_exhaustive]
 pub enum L4Proto {
     #[serde(rename = "TCP")]
     Tcp(u16),
     #[serde(rename = "UDP")]
     Udp(u16),
+    #[serde(rename = "SCTP")]
+    Sctp(u16),
+    #[serde(rename = "DCCP")]
+    Dccp(u16),
+    #[serde(rename = "ICMP")]
+    Icmp { icmp_type: u8, code: u8 },
+    #[serde(rename = "ICMPv6")]
+    Icmpv6 { icmp_type: u8, code: u8 },
+}
+impl L4Proto {
+    /// Builds an L4Proto from a raw IP protocol number and port, as stored in the ebpf maps' keys.
+    /// Returns None if the protocol is not supported.
+    pub fn new(proto: u8, port: u16) -> Option<Self> {
+        // See xdp_ip_counter_common::icmp_port()
+        let (icmp_type, code) = ((port >> 8) as u8, port as u8);
+        match proto {
+            p if p == IpProto::Tcp as u8 => Some(L4Proto::Tcp(port)),
+            p if p == IpProto::Udp as u8 => Some(L4Proto::Udp(port)),
+            p if p == IpProto::Sctp as u8 => Some(L4Proto::Sctp(port)),
+            p if p == IpProto::Dccp as u8 => Some(L4Proto::Dccp(port)),
+            p if p == IpProto::Icmp as u8 => Some(L4Proto::Icmp { icmp_type, code }),
+            p if p == IpProto::Ipv6Icmp as u8 => Some(L4Proto::Icmpv6 { icmp_type, code }),
+            _ => None,
+        }
+    }
+
+    /// Returns the port number, or None for protocols without ports.
+    pub fn port(&self) -> Option<u16> {
+        match self {
+            L4Proto::Tcp(port) | L4Proto::Udp(port) | L4Proto::Sctp(port) | L4Proto::Dccp(port) => {
+                Some(*port)
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns the ICMP/ICMPv6 type and code, or None for other protocols.
+    pub fn icmp(&self) -> Option<(u8, u8)> {
+        match self {
+            L4Proto::Icmp { icmp_type, code } | L4Proto::Icmpv6 { icmp_type, code } => {
+                Some((*icmp_type, *code))
+            }
+            _ => None,
+        }
+    }
 }
 impl Display for L4Proto {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             L4Proto::Tcp(_) => write!(f, "TCP"),
             L4Proto::Udp(_) => write!(f, "UDP"),
+            L4Proto::Sctp(_) => write!(f, "SCTP"),
+            L4Proto::Dccp(_) => write!(f, "DCCP"),
+            L4Proto::Icmp { .. } => write!(f, "ICMP"),
+            L4Proto::Icmpv6 { .. } => write!(f, "ICMPv6"),
+        }
+    }
+}
+
+/// Tunnel represents the kind of tunnel a packet was carried in, when tunnels are decapsulated.
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Serialize, Debug, Deserialize)]
+#[non_exhaustive]
+pub enum Tunnel {
+    #[serde(rename = "GRE")]
+    Gre,
+    #[serde(rename = "IPIP")]
+    Ipip,
+    #[serde(rename = "6in4")]
+    SixInFour,
+    #[serde(rename = "VXLAN")]
+    Vxlan,
+    #[serde(rename = "GENEVE")]
+    Geneve,
+}
+impl Tunnel {
+    /// Builds a Tunnel from one of the TUNNEL_* constants stored in the ebpf maps' keys.
+    /// Returns None if the packet was not carried in a tunnel.
+    pub fn new(kind: u8) -> Option<Self> {
+        match kind {
+            TUNNEL_GRE => Some(Tunnel::Gre),
+            TUNNEL_IPIP => Some(Tunnel::Ipip),
+            TUNNEL_6IN4 => Some(Tunnel::SixInFour),
+            TUNNEL_VXLAN => Some(Tunnel::Vxlan),
+            TUNNEL_GENEVE => Some(Tunnel::Geneve),
+            _ => None,
+        }
+    }
+}
+impl Display for Tunnel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Tunnel::Gre => write!(f, "GRE"),
+            Tunnel::Ipip => write!(f, "IPIP"),
+            Tunnel::SixInFour => write!(f, "6in4"),
+            Tunnel::Vxlan => write!(f, "VXLAN"),
+            Tunnel::Geneve => write!(f, "GENEVE"),
+        }
+    }
+}
+
+/// XdpMode represents how the XDP program is attached to an interface, see --xdp-mode.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum XdpMode {
+    /// Generic mode, run by the kernel after the driver allocated an skb.
+    Skb,
+    /// Native mode, run by the driver.
+    Drv,
+    /// Offloaded to the NIC.
+    Hw,
+    /// Driver mode, or generic mode if the driver does not support XDP.
+    Auto,
+}
+impl XdpMode {
+    /// Returns the flags to attach the program with, driver mode for Auto.
+    pub fn flags(&self) -> XdpFlags {
+        match self {
+            XdpMode::Skb => XdpFlags::SKB_MODE,
+            XdpMode::Drv | XdpMode::Auto => XdpFlags::DRV_MODE,
+            XdpMode::Hw => XdpFlags::HW_MODE,
+        }
+    }
+}
+impl Display for XdpMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            XdpMode::Skb => write!(f, "skb"),
+            XdpMode::Drv => write!(f, "drv"),
+            XdpMode::Hw => write!(f, "hw"),
+            XdpMode::Auto => write!(f, "auto"),
+        }
+    }
+}
+
+/// Hook represents where the program is attached, see --hook.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Hook {
+    Xdp,
+    /// TC classifier on the ingress of the clsact qdisc, seeing received packets like XDP does.
+    TcIngress,
+    /// TC classifier on the egress of the clsact qdisc, seeing sent packets and counting their destination.
+    TcEgress,
+}
+impl Hook {
+    /// Returns the name of the program to attach in the ebpf object.
+    pub fn program(&self) -> &'static str {
+        match self {
+            Hook::Xdp => "xdp_ip_counter",
+            Hook::TcIngress => "tc_ip_counter_ingress",
+            Hook::TcEgress => "tc_ip_counter_egress",
+        }
+    }
+}
+impl Display for Hook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Hook::Xdp => write!(f, "xdp"),
+            Hook::TcIngress => write!(f, "tc-ingress"),
+            Hook::TcEgress => write!(f, "tc-egress"),
+        }
+    }
+}
+
+/// Encap holds how a packet got to us: the interface it was received on, the VLAN it was tagged with and the tunnel it was
+/// carried in, if any.
+#[derive(PartialEq, Eq, Hash, Clone, Serialize, Deserialize, Debug, Default)]
+pub struct Encap {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    iface: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    vlan: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tunnel: Option<Tunnel>,
+    /// VXLAN/GENEVE VNI or GRE key.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    vni: Option<u32>,
+}
+impl Encap {
+    /// Builds an Encap from the raw values stored in the ebpf maps' keys, where 0 means absent.
+    pub fn new(vlan: u16, tunnel: u8, vni: u32) -> Self {
+        let tunnel = Tunnel::new(tunnel);
+        let vni = match tunnel {
+            // VNI 0 is valid for VXLAN and GENEVE.
+            Some(Tunnel::Vxlan) | Some(Tunnel::Geneve) => Some(vni),
+            // A GRE key of 0 is treated as no key at all.
+            Some(Tunnel::Gre) if vni != 0 => Some(vni),
+            _ => None,
+        };
+
+        Self {
+            iface: None,
+            vlan: match vlan {
+                0 => None,
+                vlan => Some(vlan),
+            },
+            tunnel,
+            vni,
+        }
+    }
+
+    /// Sets the name of the interface packets were received on.
+    pub fn with_iface(mut self, iface: String) -> Self {
+        self.iface = Some(iface);
+        self
+    }
+
+    pub fn iface(&self) -> Option<&str> {
+        self.iface.as_deref()
+    }
+
+    pub fn vlan(&self) -> Option<u16> {
+        self.vlan
+    }
+
+    pub fn tunnel(&self) -> Option<Tunnel> {
+        self.tunnel
+    }
+
+    pub fn vni(&self) -> Option<u32> {
+        self.vni
+    }
+
+    /// Returns the prometheus labels describing this Encap, each prefixed with a comma.
+    pub fn labels(&self) -> String {
+        let mut labels = String::new();
+        if let Some(iface) = &self.iface {
+            labels.push_str(format!(",iface=\"{}\"", iface).as_str());
+        }
+        if let Some(vlan) = self.vlan {
+            labels.push_str(format!(",vlan=\"{}\"", vlan).as_str());
+        }
+        if let Some(tunnel) = self.tunnel {
+            labels.push_str(format!(",tunnel=\"{}\"", tunnel).as_str());
         }
+        if let Some(vni) = self.vni {
+            labels.push_str(format!(",vni=\"{}\"", vni).as_str());
+        }
+        labels
+    }
+}
+
+impl From<&FlowMeta> for Encap {
+    fn from(meta: &FlowMeta) -> Self {
+        Encap::new(meta.vlan, meta.tunnel, meta.vni)
     }
 }
 
@@ -53,10 +377,17 @@ pub struct IpItem {
     l3_proto: L3Proto,
     #[serde(rename = "transport")]
     l4_proto: String,
-    port: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    port: Option<u16>,
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    icmp_type: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code: Option<u8>,
+    #[serde(flatten)]
+    encap: Encap,
 }
 impl IpItem {
-    pub fn new<T>(ip: T, l4_proto: &L4Proto) -> Option<Self>
+    pub fn new<T>(ip: T, l4_proto: &L4Proto, encap: Encap) -> Option<Self>
     where
         IpAddr: From<T>,
     {
@@ -66,118 +397,867 @@ impl IpItem {
                 IpAddr::V4(_) => L3Proto::Ipv4,
                 IpAddr::V6(_) => L3Proto::Ipv6,
             };
-            let port = match l4_proto {
-                L4Proto::Tcp(port) => *port,
-                L4Proto::Udp(port) => *port,
-            };
+            let icmp = l4_proto.icmp();
             return Some(Self {
                 ip,
                 l3_proto,
                 l4_proto: (*l4_proto.to_string()).to_string(),
-                port,
+                port: l4_proto.port(),
+                icmp_type: icmp.map(|(icmp_type, _)| icmp_type),
+                code: icmp.map(|(_, code)| code),
+                encap,
             });
         }
         None
     }
+
+    pub fn ip(&self) -> &IpAddr {
+        &self.ip
+    }
+
+    pub fn encap(&self) -> &Encap {
+        &self.encap
+    }
+}
+
+/// Traffic holds the number of packets and bytes (L2 header included) seen for a flow, and when it was seen.
+#[derive(PartialEq, Eq, Clone, Copy, Serialize, Deserialize, Debug, Default)]
+pub struct Traffic {
+    pub packets: u64,
+    pub bytes: u64,
+    #[serde(
+        serialize_with = "serialize_rfc3339",
+        deserialize_with = "deserialize_rfc3339",
+        skip_serializing_if = "Option::is_none",
+        default
+    )]
+    pub first_seen: Option<SystemTime>,
+    #[serde(
+        serialize_with = "serialize_rfc3339",
+        deserialize_with = "deserialize_rfc3339",
+        skip_serializing_if = "Option::is_none",
+        default
+    )]
+    pub last_seen: Option<SystemTime>,
+}
+impl Traffic {
+    /// Builds a Traffic from the stats of an ebpf map, whose timestamps are relative to `boot_time`.
+    pub fn new(stats: &FlowStats, boot_time: SystemTime) -> Self {
+        Self {
+            packets: stats.packets,
+            bytes: stats.bytes,
+            first_seen: Some(boot_time + Duration::from_nanos(stats.first_seen)),
+            last_seen: Some(boot_time + Duration::from_nanos(stats.last_seen)),
+        }
+    }
+
+    pub fn add(&mut self, other: &Traffic) {
+        self.packets += other.packets;
+        self.bytes += other.bytes;
+        self.first_seen = match (self.first_seen, other.first_seen) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        self.last_seen = match (self.last_seen, other.last_seen) {
+            (Some(a), Some(b)) => Some(a.max(b)),
+            (a, b) => a.or(b),
+        };
+    }
+}
+
+pub fn serialize_rfc3339<S>(time: &Option<SystemTime>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    match time {
+        Some(time) => serializer.collect_str(&humantime::format_rfc3339_millis(*time)),
+        None => serializer.serialize_none(),
+    }
+}
+
+fn serialize_rfc3339_required<S>(time: &SystemTime, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    serializer.collect_str(&humantime::format_rfc3339_millis(*time))
+}
+
+fn deserialize_rfc3339<'de, D>(deserializer: D) -> Result<Option<SystemTime>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    deserialize_rfc3339_required(deserializer).map(Some)
+}
+
+fn deserialize_rfc3339_required<'de, D>(deserializer: D) -> Result<SystemTime, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let time = String::deserialize(deserializer)?;
+    humantime::parse_rfc3339_weak(&time).map_err(serde::de::Error::custom)
+}
+
+/// IpEntry is an item of the /list endpoint: an IpItem along with the traffic it has sent during the last aggregate_window.
+#[derive(Serialize, Debug)]
+pub struct IpEntry<'a> {
+    #[serde(flatten)]
+    item: &'a IpItem,
+    #[serde(flatten)]
+    traffic: Traffic,
+}
+
+/// Flow abstracts over the keys of the ebpf maps, FlowV4 and FlowV6.
+pub trait Flow: aya::Pod {
+    type Addr;
+    fn l3_proto() -> L3Proto;
+    fn addr(&self) -> Self::Addr;
+    fn meta(&self) -> &FlowMeta;
+}
+impl Flow for FlowV4 {
+    type Addr = [u8; 4];
+    fn l3_proto() -> L3Proto {
+        L3Proto::Ipv4
+    }
+    fn addr(&self) -> Self::Addr {
+        self.addr
+    }
+    fn meta(&self) -> &FlowMeta {
+        &self.meta
+    }
+}
+impl Flow for FlowV6 {
+    type Addr = [u16; 8];
+    fn l3_proto() -> L3Proto {
+        L3Proto::Ipv6
+    }
+    fn addr(&self) -> Self::Addr {
+        self.addr
+    }
+    fn meta(&self) -> &FlowMeta {
+        &self.meta
+    }
+}
+
+/// IpMap holds the traffic of each IpItem, grouped by protocols.
+pub type IpMap = HashMap<L3Proto, HashMap<L4Proto, HashMap<IpItem, Traffic>>>;
+
+/// Sketches holds the HyperLogLog sketch of the sources per protocols and interface, filled in HLL mode instead of an IpMap.
+/// Only the interface of the Encap is set as VLANs and tunnels are not tracked in HLL mode.
+pub type Sketches = HashMap<(L3Proto, L4Proto, Encap), Hll>;
+
+/// Window accumulates samples over an aggregation window and holds the data of the last complete one.
+/// A sliding window instead keeps each sample of the trailing window and holds their union, updated every sample.
+pub struct Window {
+    seconds: u64,
+    label: String,
+    sliding: bool,
+    // Seconds sampled into tmp (tumbling) or ring (sliding) so far.
+    elapsed: u64,
+    // Seconds sampled since the window last completed, i.e. covered seconds it had not covered before.
+    since_complete: u64,
+    complete: bool,
+    aggr: IpMap,
+    sketches_aggr: Sketches,
+    tmp: IpMap,
+    sketches_tmp: Sketches,
+    // Samples of the trailing window along with how many seconds each one covers, oldest first.
+    ring: VecDeque<(u64, IpMap, Sketches)>,
+}
+impl Window {
+    pub fn new(seconds: u64, sliding: bool) -> Self {
+        Self {
+            seconds,
+            label: window_label(seconds),
+            sliding,
+            elapsed: 0,
+            since_complete: 0,
+            complete: false,
+            aggr: HashMap::new(),
+            sketches_aggr: HashMap::new(),
+            tmp: HashMap::new(),
+            sketches_tmp: HashMap::new(),
+            ring: VecDeque::new(),
+        }
+    }
+
+    /// Returns the window's duration formatted for the window label, e.g. "5m".
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    pub fn get_ips(&self) -> &IpMap {
+        &self.aggr
+    }
+
+    pub fn get_sketches(&self) -> &Sketches {
+        &self.sketches_aggr
+    }
+
+    /// Returns whether the last sample completed the window, i.e. its data now covers a period it had not covered before.
+    /// Tumbling windows complete whenever they are published, sliding ones once every window.
+    pub fn is_complete(&self) -> bool {
+        self.complete
+    }
+
+    /// Adds a sample covering `sampled` seconds to the window.
+    /// Returns whether the window's data has been updated, i.e. a tumbling window is complete or the window is sliding.
+    fn add_sample(&mut self, sampled: u64, ips: &IpMap, sketches: &Sketches) -> bool {
+        self.elapsed += sampled;
+        self.since_complete += sampled;
+        self.complete = self.since_complete >= self.seconds;
+        if self.complete {
+            self.since_complete = 0;
+        }
+
+        if self.sliding {
+            self.ring
+                .push_back((sampled, ips.clone(), sketches.clone()));
+            while self.elapsed > self.seconds {
+                match self.ring.pop_front() {
+                    Some((seconds, _, _)) => self.elapsed -= seconds,
+                    None => break,
+                }
+            }
+
+            self.aggr.clear();
+            self.sketches_aggr.clear();
+            for (_, ips, sketches) in self.ring.iter() {
+                merge_ips(&mut self.aggr, ips);
+                merge_sketches(&mut self.sketches_aggr, sketches);
+            }
+            true
+        } else {
+            merge_ips(&mut self.tmp, ips);
+            merge_sketches(&mut self.sketches_tmp, sketches);
+            if self.elapsed >= self.seconds {
+                self.elapsed = 0;
+                self.aggr = std::mem::take(&mut self.tmp);
+                self.sketches_aggr = std::mem::take(&mut self.sketches_tmp);
+                true
+            } else {
+                false
+            }
+        }
+    }
+
+    fn snapshot(&self) -> WindowSnapshot {
+        WindowSnapshot {
+            seconds: self.seconds,
+            sliding: self.sliding,
+            elapsed: self.elapsed,
+            since_complete: self.since_complete,
+            aggr: ips_to_records(&self.aggr),
+            sketches_aggr: sketches_to_records(&self.sketches_aggr),
+            tmp: ips_to_records(&self.tmp),
+            sketches_tmp: sketches_to_records(&self.sketches_tmp),
+            ring: self
+                .ring
+                .iter()
+                .map(|(seconds, ips, sketches)| {
+                    (*seconds, ips_to_records(ips), sketches_to_records(sketches))
+                })
+                .collect(),
+        }
+    }
+
+    fn restore(&mut self, snapshot: WindowSnapshot) {
+        self.elapsed = snapshot.elapsed;
+        self.since_complete = snapshot.since_complete;
+        self.aggr = ips_from_records(snapshot.aggr);
+        self.sketches_aggr = sketches_from_records(snapshot.sketches_aggr);
+        self.tmp = ips_from_records(snapshot.tmp);
+        self.sketches_tmp = sketches_from_records(snapshot.sketches_tmp);
+        self.ring = snapshot
+            .ring
+            .into_iter()
+            .map(|(seconds, ips, sketches)| {
+                (
+                    seconds,
+                    ips_from_records(ips),
+                    sketches_from_records(sketches),
+                )
+            })
+            .collect();
+    }
+
+    /// Accounts for `age` seconds gone by without any sample, e.g. since a restored snapshot was taken,
+    /// so that data is not served for longer than a window after it was seen.
+    fn age(&mut self, age: u64) {
+        if self.sliding {
+            // An empty sample covering the gap pushes older samples out of the trailing window
+            self.add_sample(age, &HashMap::new(), &HashMap::new());
+            self.complete = false;
+            return;
+        }
+
+        let elapsed = self.elapsed + age;
+        if elapsed >= self.seconds {
+            // The window in progress ended in the meantime, what it got is published if that was less than a window ago
+            let tmp = std::mem::take(&mut self.tmp);
+            let sketches_tmp = std::mem::take(&mut self.sketches_tmp);
+            (self.aggr, self.sketches_aggr) = match elapsed < 2 * self.seconds {
+                true => (tmp, sketches_tmp),
+                false => (HashMap::new(), HashMap::new()),
+            };
+        }
+        self.elapsed = elapsed % self.seconds;
+        self.since_complete = (self.since_complete + age) % self.seconds;
+    }
+
+    /// Summarizes the window's data per protocols and port (or ICMP type and code), as of `timestamp`.
+    fn history_rows(&self, timestamp: SystemTime) -> Vec<HistoryRow> {
+        let mut rows = Vec::new();
+        for (l3_proto, l4_map) in self.aggr.iter() {
+            for (l4_proto, ips) in l4_map {
+                // A source seen on several interfaces, VLANs or tunnels is still a single source
+                let unique_ips: HashSet<&IpAddr> = ips.keys().map(IpItem::ip).collect();
+                rows.push(HistoryRow::new(
+                    timestamp,
+                    &self.label,
+                    l3_proto,
+                    l4_proto,
+                    unique_ips.len() as u64,
+                    Some(ips.values().map(|traffic| traffic.packets).sum()),
+                ));
+            }
+        }
+        let mut sketches: HashMap<(&L3Proto, &L4Proto), Hll> = HashMap::new();
+        for ((l3_proto, l4_proto, _), hll) in self.sketches_aggr.iter() {
+            sketches
+                .entry((l3_proto, l4_proto))
+                .or_insert_with(Hll::new)
+                .merge(hll.registers());
+        }
+        for ((l3_proto, l4_proto), hll) in sketches {
+            rows.push(HistoryRow::new(
+                timestamp,
+                &self.label,
+                l3_proto,
+                l4_proto,
+                hll.estimate().round() as u64,
+                None,
+            ));
+        }
+        rows
+    }
+}
+
+/// HistoryRow is an entry of the /history endpoint: how many unique sources a port saw during a window.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HistoryRow {
+    #[serde(
+        serialize_with = "serialize_rfc3339_required",
+        deserialize_with = "deserialize_rfc3339_required"
+    )]
+    timestamp: SystemTime,
+    window: String,
+    #[serde(rename = "network")]
+    l3_proto: L3Proto,
+    #[serde(rename = "transport")]
+    l4_proto: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    port: Option<u16>,
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    icmp_type: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code: Option<u8>,
+    unique_ips: u64,
+    /// None in HLL mode, where packets are not counted.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    packets: Option<u64>,
+}
+impl HistoryRow {
+    fn new(
+        timestamp: SystemTime,
+        window: &str,
+        l3_proto: &L3Proto,
+        l4_proto: &L4Proto,
+        unique_ips: u64,
+        packets: Option<u64>,
+    ) -> Self {
+        let icmp = l4_proto.icmp();
+        Self {
+            timestamp,
+            window: window.to_string(),
+            l3_proto: l3_proto.clone(),
+            l4_proto: l4_proto.to_string(),
+            port: l4_proto.port(),
+            icmp_type: icmp.map(|(icmp_type, _)| icmp_type),
+            code: icmp.map(|(_, code)| code),
+            unique_ips,
+            packets,
+        }
+    }
+}
+
+fn merge_ips(into: &mut IpMap, from: &IpMap) {
+    for (l3_proto, l4_map) in from {
+        for (l4_proto, ips) in l4_map {
+            let into = into
+                .entry(l3_proto.clone())
+                .or_default()
+                .entry(*l4_proto)
+                .or_default();
+            for (ip_item, traffic) in ips {
+                into.entry(ip_item.clone()).or_default().add(traffic);
+            }
+        }
+    }
+}
+
+fn merge_sketches(into: &mut Sketches, from: &Sketches) {
+    for (key, hll) in from {
+        into.entry(key.clone())
+            .or_insert_with(Hll::new)
+            .merge(hll.registers());
+    }
+}
+
+fn ips_to_records(ips: &IpMap) -> Vec<IpRecord> {
+    let mut records = vec![];
+    for (l3_proto, l4_map) in ips {
+        for (l4_proto, ips) in l4_map {
+            for (ip_item, traffic) in ips {
+                records.push((
+                    l3_proto.clone(),
+                    *l4_proto,
+                    ip_item.ip,
+                    ip_item.encap.clone(),
+                    *traffic,
+                ));
+            }
+        }
+    }
+    records
+}
+
+fn ips_from_records(records: Vec<IpRecord>) -> IpMap {
+    let mut ips = IpMap::new();
+    for (l3_proto, l4_proto, ip, encap, traffic) in records {
+        if let Some(ip_item) = IpItem::new(ip, &l4_proto, encap) {
+            ips.entry(l3_proto)
+                .or_default()
+                .entry(l4_proto)
+                .or_default()
+                .insert(ip_item, traffic);
+        }
+    }
+    ips
+}
+
+fn sketches_to_records(sketches: &Sketches) -> Vec<SketchRecord> {
+    sketches
+        .iter()
+        .map(|((l3_proto, l4_proto, encap), hll)| {
+            (
+                l3_proto.clone(),
+                *l4_proto,
+                encap.clone(),
+                hll.registers().to_vec(),
+            )
+        })
+        .collect()
+}
+
+fn sketches_from_records(records: Vec<SketchRecord>) -> Sketches {
+    let mut sketches = Sketches::new();
+    for (l3_proto, l4_proto, encap, registers) in records {
+        if let Ok(registers) = HllRegisters::try_from(registers) {
+            sketches
+                .entry((l3_proto, l4_proto, encap))
+                .or_insert_with(Hll::new)
+                .merge(&registers);
+        }
+    }
+    sketches
+}
+
+/// Formats a duration in seconds the way windows are labeled, using the largest unit that divides it.
+pub fn window_label(seconds: u64) -> String {
+    if seconds % 3600 == 0 {
+        format!("{}h", seconds / 3600)
+    } else if seconds % 60 == 0 {
+        format!("{}m", seconds / 60)
+    } else {
+        format!("{}s", seconds)
+    }
 }
 
 /// LocalMap respresents maps that are used to store data in an appropriate format to be served to users
 pub struct LocalMap {
-    inner_aggr: HashMap<L3Proto, HashMap<L4Proto, HashSet<IpItem>>>,
-    inner_tmp: HashMap<L3Proto, HashMap<L4Proto, HashSet<IpItem>>>,
+    // Data read from the ebpf maps during the current sample, added to every window once the sample is complete.
+    sample: IpMap,
+    sample_sketches: Sketches,
+    // One per --window, in the order they were passed in. All are fed with the same samples.
+    windows: Vec<Window>,
+    // Traffic seen since the program started, per protocols and encapsulation. Dropped once idle, see expire_totals().
+    totals: HashMap<(L3Proto, L4Proto, Encap), Traffic>,
+    // Latest values read from the ebpf COUNTERS map, keyed by index.
+    counters: HashMap<u32, u64>,
+    // Summaries of the windows each time they were updated, oldest first. Holds up to history_size rows.
+    history: VecDeque<HistoryRow>,
+    history_size: usize,
+    // Mode the XDP program is attached in, per interface.
+    xdp_modes: BTreeMap<String, XdpMode>,
+    // Interfaces appearing and disappearing after startup, per event (see ebpf::Attachments).
+    iface_events: BTreeMap<&'static str, u64>,
+    // Samples in which a flow map was found full, per map.
+    full_maps: BTreeMap<&'static str, u64>,
 }
 impl LocalMap {
-    pub fn new() -> Self {
+    pub fn new(windows: &[u64], sliding: bool, history_size: usize) -> Self {
         Self {
-            inner_aggr: HashMap::new(),
-            inner_tmp: HashMap::new(),
+            sample: HashMap::new(),
+            sample_sketches: HashMap::new(),
+            windows: windows
+                .iter()
+                .map(|seconds| Window::new(*seconds, sliding))
+                .collect(),
+            totals: HashMap::new(),
+            counters: HashMap::new(),
+            history: VecDeque::new(),
+            history_size,
+            xdp_modes: BTreeMap::new(),
+            iface_events: BTreeMap::new(),
+            full_maps: BTreeMap::new(),
         }
     }
 
-    /// Saves the collected data from the past aggregate_window to be served for the next aggregate_window.
-    /// Check ebpf::collect() for more details.  
-    pub fn aggr(&mut self) {
-        self.inner_aggr.clear();
-        self.inner_aggr = self.inner_tmp.clone();
-        self.inner_tmp.clear();
+    /// Adds the current sample, covering `sampled` seconds, to the windows.
+    /// Tumbling windows that are now complete save their collected data to be served until they complete again,
+    /// sliding windows update theirs right away.
+    /// Check ebpf::collect() for more details.
+    pub fn aggr(&mut self, sampled: u64) {
+        let ips = std::mem::take(&mut self.sample);
+        let sketches = std::mem::take(&mut self.sample_sketches);
+        let now = SystemTime::now();
+        for window in self.windows.iter_mut() {
+            if window.add_sample(sampled, &ips, &sketches) {
+                self.history.extend(window.history_rows(now));
+            }
+        }
+        while self.history.len() > self.history_size {
+            self.history.pop_front();
+        }
     }
 
-    pub fn add_tmp<T>(&mut self, l3_proto: L3Proto, l4_proto: L4Proto, ip: T)
-    where
+    /// Returns the history rows recorded between `from` and `to` (inclusive), optionally only those of `port`.
+    pub fn get_history(
+        &self,
+        from: Option<SystemTime>,
+        to: Option<SystemTime>,
+        port: Option<u16>,
+    ) -> Vec<&HistoryRow> {
+        self.history
+            .iter()
+            .filter(|row| {
+                from.is_none_or(|from| row.timestamp >= from)
+                    && to.is_none_or(|to| row.timestamp <= to)
+                    && port.is_none_or(|port| row.port == Some(port))
+            })
+            .collect()
+    }
+
+    /// Merges a sketch read from the ebpf HLL maps into the current sample.
+    pub fn merge_sketch_tmp(
+        &mut self,
+        l3_proto: L3Proto,
+        l4_proto: L4Proto,
+        encap: Encap,
+        registers: &HllRegisters,
+    ) {
+        self.sample_sketches
+            .entry((l3_proto, l4_proto, encap))
+            .or_insert_with(Hll::new)
+            .merge(registers);
+    }
+
+    pub fn add_tmp<T>(
+        &mut self,
+        l3_proto: L3Proto,
+        l4_proto: L4Proto,
+        ip: T,
+        encap: Encap,
+        traffic: Traffic,
+    ) where
         IpAddr: From<T>,
     {
-        if let Some(ip_item) = IpItem::new(ip, &l4_proto) {
-            if let Some(map) = self.inner_tmp.get_mut(&l3_proto) {
-                if let Some(set) = map.get_mut(&l4_proto) {
-                    set.insert(ip_item);
-                } else {
-                    let mut set = HashSet::new();
-                    set.insert(ip_item);
-                    map.insert(l4_proto, set);
-                }
-            } else {
-                let mut set = HashSet::new();
-                set.insert(ip_item);
+        if let Some(ip_item) = IpItem::new(ip, &l4_proto, encap.clone()) {
+            self.totals
+                .entry((l3_proto.clone(), l4_proto, encap))
+                .or_default()
+                .add(&traffic);
 
-                let mut map = HashMap::new();
-                map.insert(l4_proto, set);
-
-                self.inner_tmp.insert(l3_proto, map);
-            }
+            self.sample
+                .entry(l3_proto)
+                .or_default()
+                .entry(l4_proto)
+                .or_default()
+                .entry(ip_item)
+                .or_default()
+                .add(&traffic);
         } else {
             // ip is local
         }
     }
 
-    pub fn get_prom_metrics(&self) -> &HashMap<L3Proto, HashMap<L4Proto, HashSet<IpItem>>> {
-        &self.inner_aggr
+    pub fn get_windows(&self) -> &[Window] {
+        &self.windows
+    }
+
+    pub fn get_totals(&self) -> &HashMap<(L3Proto, L4Proto, Encap), Traffic> {
+        &self.totals
+    }
+
+    /// Drops the totals of an interface that no longer exists.
+    pub fn remove_iface_totals(&mut self, iface: &str) {
+        self.totals
+            .retain(|(_, _, encap), _| encap.iface() != Some(iface));
+    }
+
+    /// Drops the totals last seen more than `max_idle` ago, so that ports seen once (e.g. by a scan) do not keep a
+    /// series each forever.
+    pub fn expire_totals(&mut self, max_idle: Duration) {
+        let Some(cutoff) = SystemTime::now().checked_sub(max_idle) else {
+            return;
+        };
+        self.totals.retain(|_, traffic| {
+            traffic
+                .last_seen
+                .is_none_or(|last_seen| last_seen >= cutoff)
+        });
+    }
+
+    pub fn set_xdp_mode(&mut self, iface: &str, mode: XdpMode) {
+        self.xdp_modes.insert(iface.to_string(), mode);
+    }
+
+    pub fn remove_xdp_mode(&mut self, iface: &str) {
+        self.xdp_modes.remove(iface);
+    }
+
+    pub fn get_xdp_modes(&self) -> &BTreeMap<String, XdpMode> {
+        &self.xdp_modes
+    }
+
+    pub fn count_iface_event(&mut self, event: &'static str) {
+        *self.iface_events.entry(event).or_default() += 1;
+    }
+
+    pub fn get_iface_events(&self) -> &BTreeMap<&'static str, u64> {
+        &self.iface_events
+    }
+
+    pub fn count_full_map(&mut self, map: &'static str) {
+        *self.full_maps.entry(map).or_default() += 1;
+    }
+
+    pub fn get_full_maps(&self) -> &BTreeMap<&'static str, u64> {
+        &self.full_maps
+    }
+
+    pub fn set_counter(&mut self, index: u32, value: u64) {
+        self.counters.insert(index, value);
+    }
+
+    pub fn get_counter(&self, index: u32) -> u64 {
+        self.counters.get(&index).copied().unwrap_or(0)
+    }
+
+    /// Copies what is needed to carry on after a restart, see LocalMap::restore().
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            taken: SystemTime::now(),
+            windows: self.windows.iter().map(Window::snapshot).collect(),
+            totals: self
+                .totals
+                .iter()
+                .map(|((l3_proto, l4_proto, encap), traffic)| {
+                    (l3_proto.clone(), *l4_proto, encap.clone(), *traffic)
+                })
+                .collect(),
+            history: self.history.iter().cloned().collect(),
+        }
+    }
+
+    /// Restores the windows, totals and history of a snapshot taken by a previous run.
+    /// Windows that no longer match a --window (duration and --sliding) are dropped, the others are aged by the time
+    /// elapsed since the snapshot was taken. Counters are not restored as they are read from the ebpf maps, which start over.
+    pub fn restore(&mut self, snapshot: Snapshot) {
+        let age = SystemTime::now()
+            .duration_since(snapshot.taken)
+            .unwrap_or_default()
+            .as_secs();
+        for saved in snapshot.windows {
+            if let Some(window) = self
+                .windows
+                .iter_mut()
+                .find(|window| window.seconds == saved.seconds && window.sliding == saved.sliding)
+            {
+                window.restore(saved);
+                window.age(age);
+            }
+        }
+        for (l3_proto, l4_proto, encap, traffic) in snapshot.totals {
+            self.totals
+                .entry((l3_proto, l4_proto, encap))
+                .or_default()
+                .add(&traffic);
+        }
+        self.history = snapshot.history.into();
+        while self.history.len() > self.history_size {
+            self.history.pop_front();
+        }
     }
 
-    pub fn get_ip_list(&self) -> Vec<&IpItem> {
-        let mut ip_list: Vec<&IpItem> = vec![];
-        for (_, l4_set) in self.inner_aggr.iter() {
-            for (_, set) in l4_set {
-                let mut items: Vec<&IpItem> = set.iter().collect();
+    /// Returns the IPs seen during the last complete window labeled `window`, or the first window if None.
+    /// Returns None if there is no such window.
+    pub fn get_ip_list(&self, window: Option<&str>) -> Option<Vec<IpEntry<'_>>> {
+        let window = match window {
+            Some(label) => self.windows.iter().find(|window| window.label == label)?,
+            None => self.windows.first()?,
+        };
+
+        let mut ip_list: Vec<IpEntry> = vec![];
+        for l4_map in window.aggr.values() {
+            for ips in l4_map.values() {
+                let mut items: Vec<IpEntry> = ips
+                    .iter()
+                    .map(|(item, traffic)| IpEntry {
+                        item,
+                        traffic: *traffic,
+                    })
+                    .collect();
                 ip_list.append(&mut items)
             }
         }
-        ip_list
+        Some(ip_list)
+    }
+}
+
+/// FlowMap is one generation of the ebpf maps flows are recorded into, e.g. IP_V4_0.
+pub type FlowMap<K> = maps::PerCpuHashMap<MapRefMut, K, FlowStats>;
+
+/// HllMap is one generation of the ebpf maps HyperLogLog sketches are recorded into in HLL mode.
+pub type HllMap = maps::PerCpuHashMap<MapRefMut, HllKey, HllRegisters>;
+
+/// Returns both generations of the map called `name`, i.e. `name`_0 and `name`_1.
+fn generation_maps<K: aya::Pod, V: aya::Pod>(
+    ebpf: &Bpf,
+    name: &str,
+) -> [maps::PerCpuHashMap<MapRefMut, K, V>; 2] {
+    [0, 1].map(|generation| {
+        let name = format!("{}_{}", name, generation);
+        maps::PerCpuHashMap::try_from(
+            ebpf.map_mut(&name)
+                .unwrap_or_else(|_| panic!("unable to borrow {} mutably", name)),
+        )
+        .unwrap_or_else(|_| panic!("failed to create a map from {}", name))
+    })
+}
+
+/// Empties a generation of the map called `name`, returning its entries along with their value on each CPU.
+/// Entries are read and deleted in batches with `fd` if it is known, otherwise or on kernels lacking batch operations
+/// (before Linux 5.6) they are read then removed one by one, which takes a few syscalls per entry.
+fn drain<K: aya::Pod, V: aya::Pod>(
+    map: &mut maps::PerCpuHashMap<MapRefMut, K, V>,
+    fd: Option<RawFd>,
+    name: &str,
+    generation: u32,
+) -> Vec<(K, Vec<V>)> {
+    let mut entries = Vec::new();
+    if let Some(fd) = fd {
+        match batch::drain(fd, &mut entries) {
+            Ok(()) => return entries,
+            Err(err) if err.raw_os_error() == Some(libc::EINVAL) => {}
+            Err(err) => {
+                // Entries of the failed batch are lost, the remaining ones are read on the next sample
+                info!("err draining {}_{}: {}", name, generation, err);
+                return entries;
+            }
+        }
+    }
+    for item in map.iter() {
+        match item {
+            Ok((key, values)) => entries.push((key, values.to_vec())),
+            Err(err) => info!("err reading from {}_{}: {}", name, generation, err),
+        }
     }
+    for (key, _) in entries.iter() {
+        if let Err(err) = map.remove(key) {
+            info!("err removeing from {}_{}: {}", name, generation, err);
+        }
+    }
+    entries
 }
 
 /// SharedMaps respresents maps that are used to share data between kernel-space and user-space
 pub struct SharedMaps {
     use_custom_ports: maps::Array<MapRefMut, u8>,
     custom_ports: maps::HashMap<MapRefMut, u16, u8>,
-    tcp_v4: maps::HashMap<MapRefMut, [u8; 4], u16>,
-    udp_v4: maps::HashMap<MapRefMut, [u8; 4], u16>,
-    tcp_v6: maps::HashMap<MapRefMut, [u16; 8], u16>,
-    udp_v6: maps::HashMap<MapRefMut, [u16; 8], u16>,
+    decap: maps::Array<MapRefMut, u8>,
+    ipv6_ext_max_depth: maps::Array<MapRefMut, u8>,
+    counters: maps::PerCpuArray<MapRefMut, u64>,
+    generation: maps::Array<MapRefMut, u32>,
+    // Generation the ebpf program is currently recording flows into, the other one is idle.
+    active_generation: u32,
+    ip_v4: [FlowMap<FlowV4>; 2],
+    ip_v6: [FlowMap<FlowV6>; 2],
+    icmp_v4: [FlowMap<FlowV4>; 2],
+    icmp_v6: [FlowMap<FlowV6>; 2],
+    hll_mode: maps::Array<MapRefMut, u8>,
+    hll: [HllMap; 2],
+    // Descriptors of the maps by name, for batch operations which aya lacks
+    map_fds: HashMap<String, RawFd>,
 }
 impl SharedMaps {
     pub fn new(ebpf: &Bpf) -> Self {
+        let generation: maps::Array<MapRefMut, u32> = maps::Array::try_from(
+            ebpf.map_mut("GENERATION")
+                .expect("unable to borrow GENERATION mutably"),
+        )
+        .expect("failed to create a map from GENERATION");
+        // Array maps are zero-initialized, but pinned ones carry on from the previous run
+        let active_generation = generation.get(&0, 0).unwrap_or(0);
+        let map_fds = batch::map_fds().unwrap_or_else(|err| {
+            warn!("failed to find the descriptors of the maps, draining them takes a syscall per entry: {}", err);
+            HashMap::new()
+        });
+
         Self {
-            tcp_v4: maps::HashMap::try_from(
-                ebpf.map_mut("TCP_IP_V4")
-                    .expect("unable to borrow TCP_IP_V4 mutably"),
+            ip_v4: generation_maps(ebpf, "IP_V4"),
+            ip_v6: generation_maps(ebpf, "IP_V6"),
+            icmp_v4: generation_maps(ebpf, "ICMP_V4"),
+            icmp_v6: generation_maps(ebpf, "ICMP_V6"),
+            hll: generation_maps(ebpf, "HLL"),
+
+            hll_mode: maps::Array::try_from(
+                ebpf.map_mut("HLL_MODE")
+                    .expect("unable to borrow HLL_MODE mutably"),
             )
-            .expect("failed to create a map from TCP_IP_V4"),
-            udp_v4: maps::HashMap::try_from(
-                ebpf.map_mut("UDP_IP_V4")
-                    .expect("unable to borrow UDP_IP_V4 mutably"),
+            .expect("failed to create a map from HLL_MODE"),
+
+            generation,
+            active_generation,
+            map_fds,
+
+            decap: maps::Array::try_from(
+                ebpf.map_mut("DECAP")
+                    .expect("unable to borrow DECAP mutably"),
             )
-            .expect("failed to create a map from UDP_IP_V4"),
+            .expect("failed to create a map from DECAP"),
 
-            tcp_v6: maps::HashMap::try_from(
-                ebpf.map_mut("TCP_IP_V6")
-                    .expect("unable to borrow TCP_IP_V6 mutably"),
+            ipv6_ext_max_depth: maps::Array::try_from(
+                ebpf.map_mut("IPV6_EXT_MAX_DEPTH")
+                    .expect("unable to borrow IPV6_EXT_MAX_DEPTH mutably"),
             )
-            .expect("failed to create a map from TCP_IP_V6"),
-            udp_v6: maps::HashMap::try_from(
-                ebpf.map_mut("UDP_IP_V6")
-                    .expect("unable to borrow UDP_IP_V6 mutably"),
+            .expect("failed to create a map from IPV6_EXT_MAX_DEPTH"),
+
+            counters: maps::PerCpuArray::try_from(
+                ebpf.map_mut("COUNTERS")
+                    .expect("unable to borrow COUNTERS mutably"),
             )
-            .expect("failed to create a map from UDP_IP_V6"),
+            .expect("failed to create a map from COUNTERS"),
 
             custom_ports: maps::HashMap::try_from(
                 ebpf.map_mut("CUSTOM_PORTS")
@@ -192,53 +1272,79 @@ impl SharedMaps {
             .expect("failed to create a map from USE_CUSTOM_PORTS"),
         }
     }
-    pub fn get_tcp_v4(&self) -> &maps::HashMap<MapRefMut, [u8; 4], u16> {
-        &self.tcp_v4
+    /// Returns the value of a COUNTERS slot, summed over all CPUs.
+    pub fn get_counter(&self, index: u32) -> u64 {
+        match self.counters.get(&index, 0) {
+            Ok(values) => values.iter().sum(),
+            Err(err) => {
+                info!("err reading from COUNTERS: {}", err);
+                0
+            }
+        }
     }
-    pub fn get_udp_v4(&self) -> &maps::HashMap<MapRefMut, [u8; 4], u16> {
-        &self.udp_v4
+
+    pub fn drain_ip_v4(&mut self, generation: u32) -> Vec<(FlowV4, Vec<FlowStats>)> {
+        let fd = self.map_fds.get(&format!("IP_V4_{}", generation)).copied();
+        drain(
+            &mut self.ip_v4[generation as usize],
+            fd,
+            "IP_V4",
+            generation,
+        )
     }
-    pub fn get_tcp_v6(&self) -> &maps::HashMap<MapRefMut, [u16; 8], u16> {
-        &self.tcp_v6
+    pub fn drain_ip_v6(&mut self, generation: u32) -> Vec<(FlowV6, Vec<FlowStats>)> {
+        let fd = self.map_fds.get(&format!("IP_V6_{}", generation)).copied();
+        drain(
+            &mut self.ip_v6[generation as usize],
+            fd,
+            "IP_V6",
+            generation,
+        )
     }
-    pub fn get_udp_v6(&self) -> &maps::HashMap<MapRefMut, [u16; 8], u16> {
-        &self.udp_v6
+    pub fn drain_icmp_v4(&mut self, generation: u32) -> Vec<(FlowV4, Vec<FlowStats>)> {
+        let fd = self
+            .map_fds
+            .get(&format!("ICMP_V4_{}", generation))
+            .copied();
+        drain(
+            &mut self.icmp_v4[generation as usize],
+            fd,
+            "ICMP_V4",
+            generation,
+        )
     }
-
-    pub fn remove_from_tcp_v4(&mut self, ip: &[u8; 4]) {
-        if self.tcp_v4.get(ip, 0).is_ok() {
-            match self.tcp_v4.remove(ip) {
-                Ok(_) => {}
-                Err(err) => info!("err removeing from TCP_IP_V4: {}", err),
-            }
-        }
-    }
-    pub fn remove_from_udp_v4(&mut self, ip: &[u8; 4]) {
-        if self.udp_v4.get(ip, 0).is_ok() {
-            match self.udp_v4.remove(ip) {
-                Ok(_) => {}
-                Err(err) => info!("err removeing from UDP_IP_V4: {}", err),
-            }
-        }
+    pub fn drain_icmp_v6(&mut self, generation: u32) -> Vec<(FlowV6, Vec<FlowStats>)> {
+        let fd = self
+            .map_fds
+            .get(&format!("ICMP_V6_{}", generation))
+            .copied();
+        drain(
+            &mut self.icmp_v6[generation as usize],
+            fd,
+            "ICMP_V6",
+            generation,
+        )
     }
-    pub fn remove_from_tcp_v6(&mut self, ip: &[u16; 8]) {
-        if self.tcp_v6.get(ip, 0).is_ok() {
-            match self.tcp_v6.remove(ip) {
-                Ok(_) => {}
-                Err(err) => info!("err removeing from TCP_IP_V6: {}", err),
-            }
-        }
+    pub fn drain_hll(&mut self, generation: u32) -> Vec<(HllKey, Vec<HllRegisters>)> {
+        let fd = self.map_fds.get(&format!("HLL_{}", generation)).copied();
+        drain(&mut self.hll[generation as usize], fd, "HLL", generation)
     }
-    pub fn remove_from_udp_v6(&mut self, ip: &[u16; 8]) {
-        if self.udp_v6.get(ip, 0).is_ok() {
-            match self.udp_v6.remove(ip) {
-                Ok(_) => {}
-                Err(err) => info!("err removeing from UDP_IP_V6: {}", err),
-            }
-        }
+
+    /// Makes the ebpf program record flows into the idle generation of flow maps.
+    /// Returns the generation it used to record into, which can then be drained without racing with it.
+    pub fn flip_generation(&mut self) -> Result<u32, anyhow::Error> {
+        let retired = self.active_generation;
+        self.generation.set(0, 1 - retired, 0)?;
+        self.active_generation = 1 - retired;
+        Ok(retired)
     }
 
     pub fn add_custom_ports(&mut self, ports: Option<Vec<u16>>) -> Result<(), anyhow::Error> {
+        // Pinned maps still hold the ports of the previous run
+        let stale: Vec<u16> = self.custom_ports.keys().filter_map(Result::ok).collect();
+        for port in stale {
+            self.custom_ports.remove(&port)?;
+        }
         match ports {
             Some(ports) => {
                 self.use_custom_ports.set(0, 1, 0)?;
@@ -252,4 +1358,378 @@ impl SharedMaps {
         }
         Ok(())
     }
+
+    pub fn set_hll(&mut self, hll: bool) -> Result<(), anyhow::Error> {
+        self.hll_mode.set(0, hll as u8, 0)?;
+        Ok(())
+    }
+
+    pub fn set_decap(&mut self, decap: bool) -> Result<(), anyhow::Error> {
+        self.decap.set(0, decap as u8, 0)?;
+        Ok(())
+    }
+
+    pub fn set_ipv6_ext_max_depth(&mut self, depth: u8) -> Result<(), anyhow::Error> {
+        self.ipv6_ext_max_depth.set(0, depth, 0)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_l4proto_new() {
+        assert_eq!(L4Proto::new(6, 22), Some(L4Proto::Tcp(22)));
+        assert_eq!(L4Proto::new(17, 53), Some(L4Proto::Udp(53)));
+        assert_eq!(L4Proto::new(132, 3868), Some(L4Proto::Sctp(3868)));
+        assert_eq!(L4Proto::new(33, 5004), Some(L4Proto::Dccp(5004)));
+        assert_eq!(
+            L4Proto::new(1, 0x0800),
+            Some(L4Proto::Icmp {
+                icmp_type: 8,
+                code: 0
+            })
+        );
+        assert_eq!(
+            L4Proto::new(58, 0x8000),
+            Some(L4Proto::Icmpv6 {
+                icmp_type: 128,
+                code: 0
+            })
+        );
+        assert_eq!(L4Proto::new(47, 0), None);
+    }
+
+    #[test]
+    fn test_add_tmp_multiple_ports() {
+        let mut local_map = LocalMap::new(&[60], false, 10);
+        local_map.add_tmp(
+            L3Proto::Ipv4,
+            L4Proto::Tcp(22),
+            [8, 8, 8, 8],
+            Encap::default(),
+            Traffic {
+                packets: 1,
+                bytes: 60,
+                ..Default::default()
+            },
+        );
+        local_map.add_tmp(
+            L3Proto::Ipv4,
+            L4Proto::Tcp(443),
+            [8, 8, 8, 8],
+            Encap::default(),
+            Traffic {
+                packets: 1,
+                bytes: 60,
+                ..Default::default()
+            },
+        );
+        local_map.aggr(60);
+
+        let metrics = local_map.get_windows()[0].get_ips();
+        let ipv4 = metrics.get(&L3Proto::Ipv4).unwrap();
+        assert_eq!(ipv4.get(&L4Proto::Tcp(22)).unwrap().len(), 1);
+        assert_eq!(ipv4.get(&L4Proto::Tcp(443)).unwrap().len(), 1);
+        assert_eq!(local_map.get_ip_list(None).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_add_tmp_traffic() {
+        let mut local_map = LocalMap::new(&[60], false, 10);
+        let boot_time = SystemTime::UNIX_EPOCH;
+        for (first_seen, last_seen) in [(1_000, 2_000), (500, 1_500)] {
+            local_map.add_tmp(
+                L3Proto::Ipv4,
+                L4Proto::Udp(53),
+                [8, 8, 8, 8],
+                Encap::default(),
+                Traffic::new(
+                    &FlowStats {
+                        packets: 3,
+                        bytes: 200,
+                        first_seen,
+                        last_seen,
+                    },
+                    boot_time,
+                ),
+            );
+        }
+        local_map.aggr(60);
+
+        let list = local_map.get_ip_list(None).unwrap();
+        assert_eq!(list.len(), 1);
+        assert_eq!(
+            list[0].traffic,
+            Traffic {
+                packets: 6,
+                bytes: 400,
+                first_seen: Some(boot_time + Duration::from_nanos(500)),
+                last_seen: Some(boot_time + Duration::from_nanos(2_000)),
+            }
+        );
+        assert_eq!(
+            serde_json::to_value(&list[0]).unwrap()["first_seen"],
+            "1970-01-01T00:00:00.000Z"
+        );
+
+        // Totals outlive aggregate windows
+        local_map.aggr(60);
+        let totals = local_map.get_totals();
+        assert_eq!(
+            totals
+                .get(&(L3Proto::Ipv4, L4Proto::Udp(53), Encap::default()))
+                .unwrap()
+                .packets,
+            6
+        );
+    }
+
+    #[test]
+    fn test_encap_labels() {
+        assert_eq!(Encap::new(0, 0, 0).labels(), "");
+        assert_eq!(
+            Encap::new(100, TUNNEL_VXLAN, 0).labels(),
+            ",vlan=\"100\",tunnel=\"VXLAN\",vni=\"0\""
+        );
+        assert_eq!(Encap::new(0, TUNNEL_GRE, 0).labels(), ",tunnel=\"GRE\"");
+        assert_eq!(Encap::new(0, TUNNEL_IPIP, 7).labels(), ",tunnel=\"IPIP\"");
+        assert_eq!(
+            Encap::new(100, 0, 0)
+                .with_iface("eth0".to_string())
+                .labels(),
+            ",iface=\"eth0\",vlan=\"100\""
+        );
+    }
+
+    #[test]
+    fn test_multiple_windows() {
+        let mut local_map = LocalMap::new(&[10, 30], false, 10);
+        for ip in [[8, 8, 8, 8], [1, 1, 1, 1], [9, 9, 9, 9]] {
+            local_map.add_tmp(
+                L3Proto::Ipv4,
+                L4Proto::Tcp(22),
+                ip,
+                Encap::default(),
+                Traffic::default(),
+            );
+            local_map.aggr(10);
+        }
+
+        // The 10s window only holds the last sample while the 30s one holds all three
+        assert_eq!(local_map.get_ip_list(Some("10s")).unwrap().len(), 1);
+        assert_eq!(local_map.get_ip_list(None).unwrap().len(), 1);
+        assert_eq!(local_map.get_ip_list(Some("30s")).unwrap().len(), 3);
+        assert!(local_map.get_ip_list(Some("1h")).is_none());
+    }
+
+    #[test]
+    fn test_window_label() {
+        assert_eq!(window_label(10), "10s");
+        assert_eq!(window_label(90), "90s");
+        assert_eq!(window_label(300), "5m");
+        assert_eq!(window_label(3600), "1h");
+    }
+
+    #[test]
+    fn test_sliding_window() {
+        let mut local_map = LocalMap::new(&[30], true, 10);
+        for ip in [[8, 8, 8, 8], [1, 1, 1, 1], [9, 9, 9, 9], [4, 4, 4, 4]] {
+            local_map.add_tmp(
+                L3Proto::Ipv4,
+                L4Proto::Tcp(22),
+                ip,
+                Encap::default(),
+                Traffic::default(),
+            );
+            local_map.aggr(10);
+        }
+
+        // Only the last three samples make up the trailing 30s
+        let list = local_map.get_ip_list(None).unwrap();
+        assert_eq!(list.len(), 3);
+        assert!(!list
+            .iter()
+            .any(|entry| entry.item.ip == IpAddr::from([8, 8, 8, 8])));
+
+        // Sources drop out one sample at a time
+        local_map.aggr(10);
+        assert_eq!(local_map.get_ip_list(None).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_history() {
+        let mut local_map = LocalMap::new(&[10], false, 3);
+        for port in [22, 80, 443, 8080] {
+            local_map.add_tmp(
+                L3Proto::Ipv4,
+                L4Proto::Tcp(port),
+                [8, 8, 8, 8],
+                Encap::default(),
+                Traffic {
+                    packets: 2,
+                    ..Default::default()
+                },
+            );
+            local_map.aggr(10);
+        }
+
+        // The oldest row got evicted
+        let history = local_map.get_history(None, None, None);
+        assert_eq!(history.len(), 3);
+        assert!(local_map.get_history(None, None, Some(22)).is_empty());
+
+        let history = local_map.get_history(None, None, Some(443));
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].unique_ips, 1);
+        assert_eq!(history[0].packets, Some(2));
+
+        // A source seen on several interfaces is counted once
+        for iface in ["eth0", "eth1"] {
+            local_map.add_tmp(
+                L3Proto::Ipv4,
+                L4Proto::Tcp(22),
+                [8, 8, 8, 8],
+                Encap::default().with_iface(iface.to_string()),
+                Traffic::default(),
+            );
+        }
+        local_map.aggr(10);
+        let history = local_map.get_history(None, None, Some(22));
+        assert_eq!(history[0].unique_ips, 1);
+        assert_eq!(local_map.get_ip_list(None).unwrap().len(), 2);
+
+        let future = SystemTime::now() + Duration::from_secs(60);
+        assert!(local_map.get_history(Some(future), None, None).is_empty());
+        assert_eq!(local_map.get_history(None, Some(future), None).len(), 3);
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let mut local_map = LocalMap::new(&[10, 20], false, 10);
+        for port in [22, 80] {
+            local_map.add_tmp(
+                L3Proto::Ipv4,
+                L4Proto::Tcp(port),
+                [8, 8, 8, 8],
+                Encap::default(),
+                Traffic {
+                    packets: 2,
+                    first_seen: Some(SystemTime::UNIX_EPOCH + Duration::from_millis(1500)),
+                    ..Default::default()
+                },
+            );
+        }
+        local_map.aggr(10);
+
+        // Goes through a file and back like it would across a restart
+        let snapshot = serde_json::to_vec(&local_map.snapshot()).unwrap();
+        let snapshot = serde_json::from_slice(&snapshot).unwrap();
+        let mut restored = LocalMap::new(&[20, 60], false, 10);
+        restored.restore(snapshot);
+
+        assert_eq!(restored.get_totals(), local_map.get_totals());
+        assert_eq!(restored.get_history(None, None, None).len(), 2);
+        // The 20s window was halfway through, and completes with the restored partial data
+        assert!(restored.get_ip_list(Some("20s")).unwrap().is_empty());
+        restored.aggr(10);
+        let list = restored.get_ip_list(Some("20s")).unwrap();
+        assert_eq!(list.len(), 2);
+        assert_eq!(
+            list[0].traffic.first_seen,
+            Some(SystemTime::UNIX_EPOCH + Duration::from_millis(1500))
+        );
+        // Windows that were not there before start empty
+        assert!(restored.get_ip_list(Some("1m")).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_remove_iface_totals() {
+        let mut local_map = LocalMap::new(&[10], false, 0);
+        for iface in ["eth0", "veth1"] {
+            local_map.add_tmp(
+                L3Proto::Ipv4,
+                L4Proto::Tcp(22),
+                [8, 8, 8, 8],
+                Encap::default().with_iface(iface.to_string()),
+                Traffic::default(),
+            );
+        }
+        local_map.remove_iface_totals("veth1");
+
+        let ifaces: Vec<_> = local_map
+            .get_totals()
+            .keys()
+            .map(|(_, _, encap)| encap.iface())
+            .collect();
+        assert_eq!(ifaces, vec![Some("eth0")]);
+    }
+
+    #[test]
+    fn test_expire_totals() {
+        let mut local_map = LocalMap::new(&[10], false, 0);
+        let now = SystemTime::now();
+        for (port, last_seen) in [(22, now - Duration::from_secs(7200)), (443, now)] {
+            local_map.add_tmp(
+                L3Proto::Ipv4,
+                L4Proto::Tcp(port),
+                [8, 8, 8, 8],
+                Encap::default(),
+                Traffic {
+                    packets: 1,
+                    bytes: 60,
+                    first_seen: Some(last_seen),
+                    last_seen: Some(last_seen),
+                },
+            );
+        }
+        local_map.expire_totals(Duration::from_secs(3600));
+
+        let ports: Vec<_> = local_map
+            .get_totals()
+            .keys()
+            .map(|(_, l4_proto, _)| l4_proto.port())
+            .collect();
+        assert_eq!(ports, vec![Some(443)]);
+    }
+
+    #[test]
+    fn test_restore_stale_snapshot() {
+        let sampled = |windows: &[u64], sliding: bool| {
+            let mut local_map = LocalMap::new(windows, sliding, 10);
+            local_map.add_tmp(
+                L3Proto::Ipv4,
+                L4Proto::Tcp(22),
+                [8, 8, 8, 8],
+                Encap::default(),
+                Traffic::default(),
+            );
+            local_map.aggr(10);
+            local_map
+        };
+
+        let mut snapshot = sampled(&[10, 20], false).snapshot();
+        snapshot.taken -= Duration::from_secs(15);
+        let mut restored = LocalMap::new(&[10, 20], false, 10);
+        restored.restore(snapshot);
+        // The 10s window was published more than 10s ago
+        assert!(restored.get_ip_list(Some("10s")).unwrap().is_empty());
+        // The 20s window completed 5s ago, with what it got before the restart
+        assert_eq!(restored.get_ip_list(Some("20s")).unwrap().len(), 1);
+        // and is served until the next one completes, 20s later
+        restored.aggr(10);
+        assert_eq!(restored.get_ip_list(Some("20s")).unwrap().len(), 1);
+        restored.aggr(10);
+        assert!(restored.get_ip_list(Some("20s")).unwrap().is_empty());
+
+        // Sliding windows only keep the samples still within the trailing window
+        for (age, len) in [(15, 1), (25, 0)] {
+            let mut snapshot = sampled(&[30], true).snapshot();
+            snapshot.taken -= Duration::from_secs(age);
+            let mut restored = LocalMap::new(&[30], true, 10);
+            restored.restore(snapshot);
+            assert_eq!(restored.get_ip_list(Some("30s")).unwrap().len(), len);
+        }
+    }
 }